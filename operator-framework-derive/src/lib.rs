@@ -0,0 +1,377 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+//! Derive macros for the `operator-framework` crate.
+//!
+//! Use them through the `derive` feature of `operator-framework`, instead of depending on this
+//! crate directly.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Span, TokenStream as TokenStream2};
+use quote::{quote, ToTokens};
+use syn::{ext::IdentExt, parse_macro_input, Data, DeriveInput, Error, Fields, Ident};
+
+/// The role a field plays in a condition.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Role {
+    Type,
+    Status,
+    Reason,
+    Message,
+    LastTransitionTime,
+    LastProbeTime,
+    ObservedGeneration,
+}
+
+impl Role {
+    const ALL: [Role; 7] = [
+        Role::Type,
+        Role::Status,
+        Role::Reason,
+        Role::Message,
+        Role::LastTransitionTime,
+        Role::LastProbeTime,
+        Role::ObservedGeneration,
+    ];
+
+    /// The name of the role, as used in the `#[condition(...)]` attribute.
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Type => "type",
+            Self::Status => "status",
+            Self::Reason => "reason",
+            Self::Message => "message",
+            Self::LastTransitionTime => "last_transition_time",
+            Self::LastProbeTime => "last_probe_time",
+            Self::ObservedGeneration => "observed_generation",
+        }
+    }
+
+    /// The field names which are picked up without an attribute.
+    fn default_fields(&self) -> &'static [&'static str] {
+        match self {
+            Self::Type => &["type_", "type"],
+            Self::Status => &["status"],
+            Self::Reason => &["reason"],
+            Self::Message => &["message"],
+            Self::LastTransitionTime => &["last_transition_time"],
+            Self::LastProbeTime => &["last_probe_time"],
+            Self::ObservedGeneration => &["observed_generation"],
+        }
+    }
+
+    fn from_ident(ident: &Ident) -> Option<Self> {
+        let name = ident.unraw().to_string();
+        Self::ALL.into_iter().find(|role| role.name() == name)
+    }
+}
+
+#[derive(Default)]
+struct Mapping {
+    fields: Vec<(Role, Ident)>,
+}
+
+impl Mapping {
+    fn get(&self, role: Role) -> Option<&Ident> {
+        self.fields
+            .iter()
+            .find_map(|(r, ident)| (*r == role).then_some(ident))
+    }
+
+    fn contains_field(&self, field: &Ident) -> bool {
+        self.fields.iter().any(|(_, ident)| ident == field)
+    }
+}
+
+/// Derive the `Condition` trait for a struct with named fields.
+///
+/// The fields of the condition are detected by their Kubernetes names (`type_`, `status`,
+/// `reason`, `message`, `last_transition_time`, `last_probe_time` and `observed_generation`).
+/// Other names can be mapped using the `#[condition(...)]` attribute:
+///
+/// ```ignore
+/// #[derive(Clone, Debug, Default, Serialize, Deserialize, JsonSchema, Condition)]
+/// #[serde(rename_all = "camelCase")]
+/// pub struct MyCondition {
+///     #[condition(type)]
+///     #[serde(rename = "type")]
+///     pub kind: String,
+///     pub status: String,
+///     #[condition(last_transition_time)]
+///     pub changed: Option<Time>,
+///     pub message: Option<String>,
+/// }
+/// ```
+///
+/// The type and the status fields are required, all other fields are optional. The field types
+/// must implement the field traits of the `conditions` module, or `AsRef<str>` and `From<String>`
+/// for the type field. All fields which are not part of the condition must implement `Default`.
+#[proc_macro_derive(Condition, attributes(condition))]
+pub fn derive_condition(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match condition(input) {
+        Ok(result) => result.into(),
+        Err(err) => err.into_compile_error().into(),
+    }
+}
+
+fn mapping(input: &DeriveInput) -> Result<(Mapping, Vec<Ident>), Error> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new(
+                    input.ident.span(),
+                    "Condition can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new(
+                input.ident.span(),
+                "Condition can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut mapping = Mapping::default();
+    let mut all = Vec::new();
+
+    // explicit mappings
+
+    for field in fields {
+        let ident = field.ident.clone().expect("named field");
+        all.push(ident.clone());
+
+        for attr in field
+            .attrs
+            .iter()
+            .filter(|a| a.path().is_ident("condition"))
+        {
+            attr.parse_nested_meta(|meta| {
+                let role = meta
+                    .path
+                    .get_ident()
+                    .and_then(Role::from_ident)
+                    .ok_or_else(|| meta.error("unknown condition field"))?;
+                if mapping.get(role).is_some() {
+                    return Err(meta.error(format!(
+                        "duplicate mapping for condition field '{}'",
+                        role.name()
+                    )));
+                }
+                mapping.fields.push((role, ident.clone()));
+                Ok(())
+            })?;
+        }
+    }
+
+    // implicit mappings, by name
+
+    for role in Role::ALL {
+        if mapping.get(role).is_some() {
+            continue;
+        }
+        let field = all.iter().find(|ident| {
+            !mapping.contains_field(ident)
+                && role.default_fields().contains(&&*ident.unraw().to_string())
+        });
+        if let Some(field) = field {
+            mapping.fields.push((role, field.clone()));
+        }
+    }
+
+    for role in [Role::Type, Role::Status] {
+        if mapping.get(role).is_none() {
+            return Err(Error::new(
+                input.ident.span(),
+                format!(
+                    "missing field for condition field '{}', add #[condition({})] to a field",
+                    role.name(),
+                    role.name()
+                ),
+            ));
+        }
+    }
+
+    let others = all
+        .into_iter()
+        .filter(|ident| !mapping.contains_field(ident))
+        .collect();
+
+    Ok((mapping, others))
+}
+
+fn condition(input: DeriveInput) -> Result<TokenStream2, Error> {
+    let (mapping, others) = mapping(&input)?;
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    let c = quote!(::operator_framework::conditions);
+    let chrono = quote!(::operator_framework::__private::chrono);
+    let time = quote!(::core::option::Option<#chrono::DateTime<#chrono::Utc>>);
+
+    let type_field = mapping.get(Role::Type).expect("checked");
+    let status = mapping.get(Role::Status).expect("checked");
+
+    let text = |role: Role, getter: Ident, setter: Ident| match mapping.get(role) {
+        Some(field) => quote! {
+            fn #getter(&self) -> ::core::option::Option<&str> {
+                #c::TextField::as_text(&self.#field)
+            }
+            fn #setter<S>(&mut self, value: S)
+            where
+                S: ::core::convert::Into<::core::option::Option<::std::string::String>>,
+            {
+                self.#field = #c::TextField::from_text(value.into());
+            }
+        },
+        None => quote! {
+            fn #getter(&self) -> ::core::option::Option<&str> {
+                ::core::option::Option::None
+            }
+            fn #setter<S>(&mut self, _: S)
+            where
+                S: ::core::convert::Into<::core::option::Option<::std::string::String>>,
+            {
+            }
+        },
+    };
+
+    let time_field = |role: Role, getter: Ident, setter: Ident| match mapping.get(role) {
+        Some(field) => quote! {
+            fn #getter(&self) -> #time {
+                #c::TimeField::to_time(&self.#field)
+            }
+            fn #setter<T>(&mut self, time: T)
+            where
+                T: ::core::convert::Into<#time>,
+            {
+                self.#field = #c::TimeField::from_time(time.into());
+            }
+        },
+        None => quote! {
+            fn #getter(&self) -> #time {
+                ::core::option::Option::None
+            }
+            fn #setter<T>(&mut self, _: T)
+            where
+                T: ::core::convert::Into<#time>,
+            {
+            }
+        },
+    };
+
+    let ident = |name: &str| Ident::new(name, Span::call_site());
+
+    let reason = text(Role::Reason, ident("reason"), ident("set_reason"));
+    let message = text(Role::Message, ident("message"), ident("set_message"));
+    let last_transition_time = time_field(
+        Role::LastTransitionTime,
+        ident("last_transition_time"),
+        ident("set_last_transition_time"),
+    );
+    let last_probe_time = time_field(
+        Role::LastProbeTime,
+        ident("last_probe_time"),
+        ident("set_last_probe_time"),
+    );
+
+    let observed_generation = match mapping.get(Role::ObservedGeneration) {
+        Some(field) => quote! {
+            fn observed_generation(&self) -> ::core::option::Option<i64> {
+                #c::GenerationField::to_generation(&self.#field)
+            }
+            fn set_observed_generation<S>(&mut self, observed_generation: S)
+            where
+                S: ::core::convert::Into<::core::option::Option<i64>>,
+            {
+                self.#field = #c::GenerationField::from_generation(observed_generation.into());
+            }
+        },
+        None => quote! {
+            fn observed_generation(&self) -> ::core::option::Option<i64> {
+                ::core::option::Option::None
+            }
+            fn set_observed_generation<S>(&mut self, _: S)
+            where
+                S: ::core::convert::Into<::core::option::Option<i64>>,
+            {
+            }
+        },
+    };
+
+    // the initializers of all fields, for creating a new condition
+
+    let init = mapping
+        .fields
+        .iter()
+        .map(|(role, field)| {
+            let value = match role {
+                Role::Type => quote!(::core::convert::From::from(r#type)),
+                Role::Status => quote!(#c::StatusField::from_state(state)),
+                Role::Reason => quote!(#c::TextField::from_text(reason)),
+                Role::Message => quote!(#c::TextField::from_text(message)),
+                Role::LastTransitionTime | Role::LastProbeTime => {
+                    quote!(#c::TimeField::from_time(::core::option::Option::Some(now)))
+                }
+                Role::ObservedGeneration => {
+                    quote!(#c::GenerationField::from_generation(observed_generation))
+                }
+            };
+            quote!(#field: #value)
+        })
+        .chain(
+            others
+                .iter()
+                .map(|field| quote!(#field: ::core::default::Default::default())),
+        );
+
+    Ok(quote! {
+        impl #impl_generics #c::Condition for #name #ty_generics #where_clause {
+            fn state(&self) -> #c::State {
+                #c::StatusField::to_state(&self.#status)
+            }
+
+            fn set_state(&mut self, state: #c::State) {
+                self.#status = #c::StatusField::from_state(state);
+            }
+
+            fn r#type(&self) -> &str {
+                ::core::convert::AsRef::<str>::as_ref(&self.#type_field)
+            }
+
+            #reason
+            #message
+            #last_transition_time
+            #last_probe_time
+            #observed_generation
+
+            #[allow(unused_variables)]
+            fn from(
+                r#type: ::std::string::String,
+                state: #c::State,
+                reason: ::core::option::Option<::std::string::String>,
+                message: ::core::option::Option<::std::string::String>,
+                observed_generation: ::core::option::Option<i64>,
+                now: #chrono::DateTime<#chrono::Utc>,
+            ) -> Self {
+                Self {
+                    #(#init,)*
+                }
+            }
+        }
+    }
+    .into_token_stream())
+}
@@ -0,0 +1,143 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+use super::{Condition, ConditionsExt, State, StateBuilder, StateDetails, Transition};
+use chrono::{DateTime, Utc};
+
+/// Aggregate a summary condition from a set of other conditions.
+pub trait AggregateConditions: ConditionsExt {
+    /// Aggregate the state of the provided condition types.
+    ///
+    /// The result is `True` if all conditions are `True`. Otherwise the state, reason and message
+    /// are taken from the first condition (in the order of `types`) which is not `True`. If that
+    /// condition has no reason, the type of the condition is used instead. A missing condition is
+    /// considered `Unknown`.
+    fn aggregate<I, S>(&self, types: I) -> StateDetails
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<str>,
+    {
+        for r#type in types {
+            let r#type = r#type.as_ref();
+            match self.get_condition(r#type) {
+                Some(condition) if condition.state() == State::True => {}
+                Some(condition) => {
+                    let reason = condition
+                        .reason()
+                        .filter(|reason| !reason.is_empty())
+                        .unwrap_or(r#type);
+                    let message = condition.message().filter(|message| !message.is_empty());
+                    return condition
+                        .state()
+                        .with_reason(reason)
+                        .with_message_opt(message.map(ToString::to_string));
+                }
+                None => {
+                    return State::Unknown
+                        .with_reason(r#type)
+                        .with_message(format!("Condition '{}' is missing", r#type))
+                }
+            }
+        }
+
+        State::True.into()
+    }
+
+    /// Set the condition `r#type` to the aggregated state of the provided condition types.
+    fn update_aggregate<S, I, T>(&mut self, r#type: S, types: I) -> Option<Transition>
+    where
+        S: AsRef<str>,
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+    {
+        self.update_aggregate_on(r#type, types, Utc::now())
+    }
+
+    fn update_aggregate_on<S, I, T, DT>(
+        &mut self,
+        r#type: S,
+        types: I,
+        now: DT,
+    ) -> Option<Transition>
+    where
+        S: AsRef<str>,
+        I: IntoIterator<Item = T>,
+        T: AsRef<str>,
+        DT: Into<DateTime<Utc>>,
+    {
+        let state = self.aggregate(types);
+        self.update_condition_on(r#type, state, now)
+    }
+}
+
+impl<T> AggregateConditions for T where T: ConditionsExt {}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::conditions::Conditions;
+    use k8s_openapi::api::batch::v1::JobCondition;
+
+    #[test]
+    fn test_aggregate() {
+        let mut conditions: Option<Vec<JobCondition>> = None;
+
+        assert_eq!(conditions.aggregate::<_, &str>([]), State::True.into());
+        assert_eq!(
+            conditions.aggregate(["Foo"]),
+            State::Unknown
+                .with_reason("Foo")
+                .with_message("Condition 'Foo' is missing")
+        );
+
+        conditions.update_condition("Foo", State::True);
+        conditions.update_condition("Bar", State::False);
+        conditions.update_condition(
+            "Baz",
+            State::Unknown
+                .with_reason("Waiting")
+                .with_message("Waiting for baz"),
+        );
+
+        assert_eq!(conditions.aggregate(["Foo"]), State::True.into());
+        assert_eq!(
+            conditions.aggregate(["Foo", "Bar", "Baz"]),
+            State::False.with_reason("Bar")
+        );
+        assert_eq!(
+            conditions.aggregate(["Foo", "Baz", "Bar"]),
+            State::Unknown
+                .with_reason("Waiting")
+                .with_message("Waiting for baz")
+        );
+    }
+
+    #[test]
+    fn test_update_aggregate() {
+        let mut conditions: Vec<JobCondition> = vec![];
+        conditions.update_condition("Foo", State::True);
+        conditions.update_condition("Bar", State::False.with_reason("Failed"));
+
+        conditions.update_aggregate("Ready", ["Foo", "Bar"]);
+        let ready = conditions.iter().find(|c| c.type_ == "Ready").unwrap();
+        assert_eq!(ready.status, "False");
+        assert_eq!(ready.reason.as_deref(), Some("Failed"));
+
+        conditions.update_condition("Bar", State::True);
+        conditions.update_aggregate("Ready", ["Foo", "Bar"]);
+        let ready = conditions.iter().find(|c| c.type_ == "Ready").unwrap();
+        assert_eq!(ready.status, "True");
+        assert_eq!(ready.reason, None);
+    }
+}
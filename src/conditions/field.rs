@@ -0,0 +1,308 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+//! Conversions of condition fields, used by `#[derive(Condition)]`.
+//!
+//! Implement these traits for custom field types, in order to use them in a derived condition.
+
+use super::State;
+use chrono::{DateTime, Utc};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Time;
+
+/// A field holding the status of a condition.
+pub trait StatusField {
+    fn to_state(&self) -> State;
+    fn from_state(state: State) -> Self;
+}
+
+impl StatusField for String {
+    fn to_state(&self) -> State {
+        match self.as_str() {
+            "True" => State::True,
+            "False" => State::False,
+            _ => State::Unknown,
+        }
+    }
+
+    fn from_state(state: State) -> Self {
+        state.to_string()
+    }
+}
+
+/// A field holding a text, like the reason or message.
+pub trait TextField {
+    fn as_text(&self) -> Option<&str>;
+    fn from_text(text: Option<String>) -> Self;
+}
+
+impl TextField for String {
+    fn as_text(&self) -> Option<&str> {
+        Some(self)
+    }
+
+    fn from_text(text: Option<String>) -> Self {
+        text.unwrap_or_default()
+    }
+}
+
+impl TextField for Option<String> {
+    fn as_text(&self) -> Option<&str> {
+        self.as_deref()
+    }
+
+    fn from_text(text: Option<String>) -> Self {
+        text
+    }
+}
+
+/// A field holding a timestamp.
+///
+/// Non-optional fields use the current time when being set to `None`.
+pub trait TimeField {
+    fn to_time(&self) -> Option<DateTime<Utc>>;
+    fn from_time(time: Option<DateTime<Utc>>) -> Self;
+}
+
+impl TimeField for DateTime<Utc> {
+    fn to_time(&self) -> Option<DateTime<Utc>> {
+        Some(*self)
+    }
+
+    fn from_time(time: Option<DateTime<Utc>>) -> Self {
+        time.unwrap_or_else(Utc::now)
+    }
+}
+
+impl TimeField for Option<DateTime<Utc>> {
+    fn to_time(&self) -> Option<DateTime<Utc>> {
+        *self
+    }
+
+    fn from_time(time: Option<DateTime<Utc>>) -> Self {
+        time
+    }
+}
+
+impl TimeField for Time {
+    fn to_time(&self) -> Option<DateTime<Utc>> {
+        Some(self.0)
+    }
+
+    fn from_time(time: Option<DateTime<Utc>>) -> Self {
+        Time(time.unwrap_or_else(Utc::now))
+    }
+}
+
+impl TimeField for Option<Time> {
+    fn to_time(&self) -> Option<DateTime<Utc>> {
+        self.as_ref().map(|t| t.0)
+    }
+
+    fn from_time(time: Option<DateTime<Utc>>) -> Self {
+        time.map(Time)
+    }
+}
+
+/// A field holding the observed generation.
+pub trait GenerationField {
+    fn to_generation(&self) -> Option<i64>;
+    fn from_generation(generation: Option<i64>) -> Self;
+}
+
+impl GenerationField for i64 {
+    fn to_generation(&self) -> Option<i64> {
+        Some(*self)
+    }
+
+    fn from_generation(generation: Option<i64>) -> Self {
+        generation.unwrap_or_default()
+    }
+}
+
+impl GenerationField for Option<i64> {
+    fn to_generation(&self) -> Option<i64> {
+        *self
+    }
+
+    fn from_generation(generation: Option<i64>) -> Self {
+        generation
+    }
+}
+
+#[cfg(all(test, feature = "derive"))]
+mod test {
+
+    use super::*;
+    use crate::conditions::{Condition, Conditions, ConditionsExt, StateBuilder};
+
+    #[derive(Clone, Debug, Default, PartialEq, Condition)]
+    struct KubeCondition {
+        type_: String,
+        status: String,
+        reason: Option<String>,
+        message: Option<String>,
+        last_transition_time: Option<Time>,
+        observed_generation: Option<i64>,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq, Condition)]
+    struct CustomCondition {
+        #[condition(type)]
+        kind: String,
+        #[condition(status)]
+        state: String,
+        #[condition(message)]
+        description: String,
+        #[condition(last_transition_time)]
+        changed: DateTime<Utc>,
+        #[condition(last_probe_time)]
+        probed: DateTime<Utc>,
+        #[condition(observed_generation)]
+        generation: i64,
+        other: u32,
+    }
+
+    #[test]
+    fn test_derive_default_names() {
+        let now = Utc::now();
+        let mut conditions: Vec<KubeCondition> = vec![];
+        conditions.update_condition_on(
+            "Ready",
+            State::False.with_reason("Foo").with_observed(1),
+            now,
+        );
+
+        assert_eq!(
+            conditions,
+            vec![KubeCondition {
+                type_: "Ready".into(),
+                status: "False".into(),
+                reason: Some("Foo".into()),
+                message: None,
+                last_transition_time: Some(Time(now)),
+                observed_generation: Some(1),
+            }]
+        );
+        assert_eq!(conditions[0].last_probe_time(), None);
+    }
+
+    #[test]
+    fn test_derive_custom_names() {
+        let now = Utc::now();
+        let mut conditions: Vec<CustomCondition> = vec![];
+        conditions.update_condition_on(
+            "Ready",
+            State::True.with_reason("Foo").with_message("Bar"),
+            now,
+        );
+
+        assert_eq!(
+            conditions,
+            vec![CustomCondition {
+                kind: "Ready".into(),
+                state: "True".into(),
+                description: "Bar".into(),
+                changed: now,
+                probed: now,
+                generation: 0,
+                other: 0,
+            }]
+        );
+        assert_eq!(conditions[0].reason(), None);
+        assert!(conditions.is_true("Ready"));
+    }
+
+    #[cfg(feature = "schemas")]
+    mod crd {
+
+        use super::*;
+        use chrono::TimeZone;
+        use kube::{CustomResource, CustomResourceExt};
+        use schemars::JsonSchema;
+        use serde_derive::{Deserialize, Serialize};
+        use serde_json::json;
+
+        #[derive(
+            Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema, Condition,
+        )]
+        #[serde(rename_all = "camelCase")]
+        pub struct ExampleCondition {
+            #[condition(type)]
+            #[serde(rename = "type")]
+            pub kind: String,
+            pub status: String,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub reason: Option<String>,
+            #[condition(last_transition_time)]
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub changed: Option<Time>,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            pub observed_generation: Option<i64>,
+        }
+
+        #[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize, JsonSchema)]
+        pub struct ExampleStatus {
+            #[serde(default, skip_serializing_if = "Vec::is_empty")]
+            pub conditions: Vec<ExampleCondition>,
+        }
+
+        #[derive(CustomResource, Clone, Debug, Default, Serialize, Deserialize, JsonSchema)]
+        #[kube(
+            group = "example.com",
+            version = "v1",
+            kind = "Example",
+            namespaced,
+            status = "ExampleStatus"
+        )]
+        pub struct ExampleSpec {}
+
+        #[test]
+        fn test_custom_resource() {
+            // timestamps are serialized with a precision of seconds
+            let now = Utc.timestamp_opt(Utc::now().timestamp(), 0).unwrap();
+            let mut example = Example::new("foo", ExampleSpec {});
+            example
+                .status
+                .get_or_insert_with(Default::default)
+                .conditions
+                .update_condition_on("Ready", State::True.with_observed(2), now);
+
+            let json = serde_json::to_value(&example).unwrap();
+            assert_eq!(
+                json["status"],
+                json!({
+                    "conditions": [{
+                        "type": "Ready",
+                        "status": "True",
+                        "changed": Time(now),
+                        "observedGeneration": 2,
+                    }]
+                })
+            );
+
+            let parsed: Example = serde_json::from_value(json).unwrap();
+            assert_eq!(parsed.status, example.status);
+
+            let crd = serde_json::to_value(Example::crd()).unwrap();
+            let condition = &crd.pointer(
+                "/spec/versions/0/schema/openAPIV3Schema/properties/status/properties/conditions/items",
+            )
+            .unwrap();
+            assert_eq!(condition["required"], json!(["status", "type"]));
+            assert_eq!(
+                condition["properties"]["changed"]["format"],
+                json!("date-time")
+            );
+        }
+    }
+}
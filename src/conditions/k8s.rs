@@ -4,3 +4,48 @@ k8s_openapi::k8s_if_ge_1_20! {
 
 crate::condition!(k8s_openapi::api::batch::v1::JobCondition[probe]);
 crate::condition!(k8s_openapi::api::apps::v1::StatefulSetCondition);
+
+// apps
+
+crate::condition!(k8s_openapi::api::apps::v1::DaemonSetCondition);
+crate::condition!(k8s_openapi::api::apps::v1::DeploymentCondition[probe = last_update_time]);
+crate::condition!(k8s_openapi::api::apps::v1::ReplicaSetCondition);
+
+// core
+
+crate::condition!(k8s_openapi::api::core::v1::NamespaceCondition);
+crate::condition!(k8s_openapi::api::core::v1::NodeCondition[probe = last_heartbeat_time]);
+crate::condition!(k8s_openapi::api::core::v1::PersistentVolumeClaimCondition[probe]);
+crate::condition!(k8s_openapi::api::core::v1::PodCondition[probe]);
+
+// autoscaling
+
+k8s_openapi::k8s_if_le_1_24! {
+    crate::condition!(k8s_openapi::api::autoscaling::v2beta1::HorizontalPodAutoscalerCondition);
+}
+crate::condition!(k8s_openapi::api::autoscaling::v2beta2::HorizontalPodAutoscalerCondition);
+k8s_openapi::k8s_if_ge_1_23! {
+    crate::condition!(k8s_openapi::api::autoscaling::v2::HorizontalPodAutoscalerCondition);
+}
+
+// apiextensions
+
+crate::condition!(
+    k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1::CustomResourceDefinitionCondition
+);
+k8s_openapi::k8s_if_le_1_21! {
+    crate::condition!(
+        k8s_openapi::apiextensions_apiserver::pkg::apis::apiextensions::v1beta1::CustomResourceDefinitionCondition
+    );
+}
+
+// apiregistration
+
+crate::condition!(
+    k8s_openapi::kube_aggregator::pkg::apis::apiregistration::v1::APIServiceCondition
+);
+k8s_openapi::k8s_if_le_1_21! {
+    crate::condition!(
+        k8s_openapi::kube_aggregator::pkg::apis::apiregistration::v1beta1::APIServiceCondition
+    );
+}
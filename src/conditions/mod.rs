@@ -11,9 +11,17 @@
  * SPDX-License-Identifier: EPL-2.0
  */
 
+mod aggregate;
+mod field;
 mod k8s;
+mod workload;
 
-pub use k8s::*;
+pub use aggregate::*;
+pub use field::*;
+pub use workload::*;
+
+#[cfg(feature = "derive")]
+pub use operator_framework_derive::Condition;
 
 use crate::utils::UseOrCreate;
 use chrono::{DateTime, Utc};
@@ -249,6 +257,9 @@ macro_rules! condition {
         }
     };
     ($n:ty [probe]) => {
+        $crate::condition!($n[probe = last_probe_time]);
+    };
+    ($n:ty [probe = $probe:ident]) => {
         impl $crate::conditions::Condition for $n {
             fn from(
                 r#type: String,
@@ -260,7 +271,7 @@ macro_rules! condition {
             ) -> Self {
                 let now = k8s_openapi::apimachinery::pkg::apis::meta::v1::Time(now);
                 Self {
-                    last_probe_time: Some(now.clone()),
+                    $probe: Some(now.clone()),
                     last_transition_time: Some(now),
                     reason,
                     message,
@@ -270,14 +281,14 @@ macro_rules! condition {
             }
 
             fn last_probe_time(&self) -> Option<chrono::DateTime<chrono::Utc>> {
-                self.last_probe_time.as_ref().map(|t| t.0)
+                self.$probe.as_ref().map(|t| t.0)
             }
 
             fn set_last_probe_time<T>(&mut self, time: T)
             where
                 T: Into<Option<chrono::DateTime<chrono::Utc>>>,
             {
-                self.last_probe_time = time
+                self.$probe = time
                     .into()
                     .map(k8s_openapi::apimachinery::pkg::apis::meta::v1::Time);
             }
@@ -398,8 +409,22 @@ macro_rules! condition {
     };
 }
 
+/// A change of the state of a condition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Transition {
+    /// The type of the condition.
+    pub r#type: String,
+    /// The previous state, `None` if the condition was added.
+    pub from: Option<State>,
+    /// The new state.
+    pub to: State,
+    pub reason: Option<String>,
+    pub message: Option<String>,
+}
+
 pub trait Conditions {
-    fn update_condition<S, D>(&mut self, r#type: S, state: D)
+    /// Update a condition, returning the transition if the state changed.
+    fn update_condition<S, D>(&mut self, r#type: S, state: D) -> Option<Transition>
     where
         S: AsRef<str>,
         D: Into<StateDetails>,
@@ -407,24 +432,122 @@ pub trait Conditions {
         self.update_condition_on(r#type, state, Utc::now())
     }
 
-    fn update_condition_on<S, D, DT>(&mut self, r#type: S, state: D, now: DT)
+    fn update_condition_on<S, D, DT>(&mut self, r#type: S, state: D, now: DT) -> Option<Transition>
     where
         S: AsRef<str>,
         D: Into<StateDetails>,
         DT: Into<DateTime<Utc>>;
 }
 
+/// Inspecting and maintaining a list of conditions.
+///
+/// This extends [`Conditions`], and is implemented for `Vec` and `Option<Vec>` of [`Condition`]s.
+pub trait ConditionsExt: Conditions {
+    type Condition: Condition;
+
+    /// Get the condition of the provided type.
+    fn get_condition<S>(&self, r#type: S) -> Option<&Self::Condition>
+    where
+        S: AsRef<str>;
+
+    /// Remove the condition of the provided type, returning it if it was present.
+    fn remove_condition<S>(&mut self, r#type: S) -> Option<Self::Condition>
+    where
+        S: AsRef<str>;
+
+    /// Check if the condition of the provided type is present and `True`.
+    fn is_true<S>(&self, r#type: S) -> bool
+    where
+        S: AsRef<str>,
+    {
+        self.get_condition(r#type)
+            .map(|c| c.state() == State::True)
+            .unwrap_or_default()
+    }
+
+    /// Only keep the conditions for which the predicate returns `true`.
+    ///
+    /// This can be used to prune conditions which are no longer managed.
+    fn retain_conditions<F>(&mut self, f: F)
+    where
+        F: FnMut(&Self::Condition) -> bool;
+
+    /// Mark all conditions which were observed for an older generation as `Unknown`.
+    ///
+    /// Conditions which don't track the observed generation are left unchanged.
+    fn mark_outdated(&mut self, generation: i64) {
+        self.mark_outdated_on(generation, Utc::now())
+    }
+
+    fn mark_outdated_on<DT>(&mut self, generation: i64, now: DT)
+    where
+        DT: Into<DateTime<Utc>>;
+}
+
+/// Access the conditions of a type, e.g. the status section of a resource.
+pub trait HasConditions {
+    type Conditions: Conditions;
+
+    fn conditions_mut(&mut self) -> &mut Self::Conditions;
+}
+
 impl<C> Conditions for Option<Vec<C>>
 where
     C: Condition,
 {
-    fn update_condition_on<S, D, DT>(&mut self, r#type: S, state: D, now: DT)
+    fn update_condition_on<S, D, DT>(&mut self, r#type: S, state: D, now: DT) -> Option<Transition>
     where
         S: AsRef<str>,
         D: Into<StateDetails>,
         DT: Into<DateTime<Utc>>,
     {
-        self.use_or_create(|conditions| conditions.update_condition_on(r#type, state, now));
+        self.use_or_create(|conditions| conditions.update_condition_on(r#type, state, now))
+    }
+}
+
+impl<C> ConditionsExt for Option<Vec<C>>
+where
+    C: Condition,
+{
+    type Condition = C;
+
+    fn get_condition<S>(&self, r#type: S) -> Option<&C>
+    where
+        S: AsRef<str>,
+    {
+        self.as_ref()?.get_condition(r#type)
+    }
+
+    fn remove_condition<S>(&mut self, r#type: S) -> Option<C>
+    where
+        S: AsRef<str>,
+    {
+        let result = self.as_mut()?.remove_condition(r#type);
+        if matches!(self, Some(conditions) if conditions.is_empty()) {
+            *self = None;
+        }
+        result
+    }
+
+    fn retain_conditions<F>(&mut self, f: F)
+    where
+        F: FnMut(&C) -> bool,
+    {
+        if let Some(conditions) = self {
+            conditions.retain_conditions(f);
+            if conditions.is_empty() {
+                *self = None;
+            }
+        }
+    }
+
+    fn mark_outdated_on<DT>(&mut self, generation: i64, now: DT)
+    where
+        DT: Into<DateTime<Utc>>,
+    {
+        if let Some(conditions) = self {
+            conditions.mark_outdated_on(generation, now);
+        }
     }
 }
 
@@ -432,7 +555,7 @@ impl<C> Conditions for Vec<C>
 where
     C: Condition,
 {
-    fn update_condition_on<S, D, DT>(&mut self, r#type: S, state: D, now: DT)
+    fn update_condition_on<S, D, DT>(&mut self, r#type: S, state: D, now: DT) -> Option<Transition>
     where
         S: AsRef<str>,
         D: Into<StateDetails>,
@@ -441,9 +564,19 @@ where
         let info = state.into();
         let now = now.into();
 
+        let transition = |from| Transition {
+            r#type: r#type.as_ref().to_string(),
+            from,
+            to: info.state,
+            reason: info.reason.clone(),
+            message: info.message.clone(),
+        };
+
         for condition in self.into_iter() {
             if condition.r#type() == r#type.as_ref() {
+                let mut result = None;
                 if condition.state() != info.state {
+                    result = Some(transition(Some(condition.state())));
                     condition.set_last_transition_time(now);
                     condition.set_state(info.state);
                 }
@@ -452,12 +585,14 @@ where
                 condition.set_message(info.message);
                 condition.set_observed_generation(info.observed_generation);
 
-                return;
+                return result;
             }
         }
 
         // did not find entry so far
 
+        let result = transition(None);
+
         self.push(C::from(
             r#type.as_ref().to_string(),
             info.state,
@@ -466,6 +601,61 @@ where
             info.observed_generation,
             now,
         ));
+
+        Some(result)
+    }
+}
+
+impl<C> ConditionsExt for Vec<C>
+where
+    C: Condition,
+{
+    type Condition = C;
+
+    fn get_condition<S>(&self, r#type: S) -> Option<&C>
+    where
+        S: AsRef<str>,
+    {
+        self.iter().find(|c| c.r#type() == r#type.as_ref())
+    }
+
+    fn remove_condition<S>(&mut self, r#type: S) -> Option<C>
+    where
+        S: AsRef<str>,
+    {
+        let index = self.iter().position(|c| c.r#type() == r#type.as_ref())?;
+        Some(self.remove(index))
+    }
+
+    fn retain_conditions<F>(&mut self, f: F)
+    where
+        F: FnMut(&C) -> bool,
+    {
+        self.retain(f);
+    }
+
+    fn mark_outdated_on<DT>(&mut self, generation: i64, now: DT)
+    where
+        DT: Into<DateTime<Utc>>,
+    {
+        let now = now.into();
+
+        for condition in self.iter_mut() {
+            let observed = match condition.observed_generation() {
+                Some(observed) if observed < generation => observed,
+                _ => continue,
+            };
+
+            if condition.state() != State::Unknown {
+                condition.set_last_transition_time(now);
+                condition.set_state(State::Unknown);
+            }
+            condition.set_reason("Outdated".to_string());
+            condition.set_message(format!(
+                "Condition was observed for generation {}, current generation is {}",
+                observed, generation
+            ));
+        }
     }
 }
 
@@ -653,4 +843,167 @@ mod test {
             );
         });
     }
+
+    #[test]
+    fn test_lookup() {
+        let mut conditions: Option<Vec<JobCondition>> = None;
+        assert!(conditions.get_condition("Ready").is_none());
+        assert!(!conditions.is_true("Ready"));
+
+        conditions.update_condition("Ready", State::True);
+        conditions.update_condition("Foo", State::False);
+
+        assert_eq!(
+            conditions.get_condition("Foo").map(|c| c.status.as_str()),
+            Some("False")
+        );
+        assert!(conditions.is_true("Ready"));
+        assert!(!conditions.is_true("Foo"));
+        assert!(!conditions.is_true("Bar"));
+    }
+
+    #[test]
+    fn test_remove() {
+        let mut conditions: Option<Vec<JobCondition>> = None;
+        assert!(conditions.remove_condition("Ready").is_none());
+
+        conditions.update_condition("Ready", State::True);
+        conditions.update_condition("Foo", State::False);
+
+        assert_eq!(
+            conditions.remove_condition("Foo").map(|c| c.type_),
+            Some("Foo".to_string())
+        );
+        assert!(conditions.remove_condition("Foo").is_none());
+        assert_eq!(conditions.as_ref().map(|c| c.len()), Some(1));
+
+        conditions.remove_condition("Ready");
+        assert_eq!(conditions, None);
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut conditions: Option<Vec<JobCondition>> = None;
+        conditions.update_condition("Ready", State::True);
+        conditions.update_condition("Foo", State::False);
+        conditions.update_condition("Bar", State::False);
+
+        conditions.retain_conditions(|c| ["Ready", "Bar"].contains(&c.type_.as_str()));
+        assert_eq!(
+            conditions
+                .iter()
+                .flatten()
+                .map(|c| c.type_.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Ready", "Bar"]
+        );
+
+        conditions.retain_conditions(|_| false);
+        assert_eq!(conditions, None);
+    }
+
+    k8s_openapi::k8s_if_ge_1_20! {
+        #[test]
+        fn test_mark_outdated() {
+            use k8s_openapi::apimachinery::pkg::apis::meta::v1::Condition as MetaCondition;
+
+            let mut conditions: Vec<MetaCondition> = vec![];
+            let now = Utc::now();
+            conditions.update_condition_on("Ready", State::True.with_observed(1), now);
+            conditions.update_condition_on("Foo", State::True.with_observed(2), now);
+
+            let now_2 = Utc::now();
+            conditions.mark_outdated_on(2, now_2);
+
+            let ready = conditions.get_condition("Ready").unwrap();
+            assert_eq!(ready.state(), State::Unknown);
+            assert_eq!(ready.reason, "Outdated");
+            assert_eq!(ready.last_transition_time, Time(now_2));
+
+            let foo = conditions.get_condition("Foo").unwrap();
+            assert_eq!(foo.state(), State::True);
+            assert_eq!(foo.last_transition_time, Time(now));
+        }
+    }
+
+    #[test]
+    fn test_probe_field() {
+        use k8s_openapi::api::apps::v1::DeploymentCondition;
+        use k8s_openapi::api::core::v1::NodeCondition;
+
+        let now = Utc::now();
+
+        let mut conditions: Vec<DeploymentCondition> = vec![];
+        conditions.update_condition_on("Available", State::True, now);
+        assert_eq!(conditions[0].last_update_time, Some(Time(now)));
+        assert_eq!(conditions[0].last_probe_time(), Some(now));
+
+        let mut conditions: Vec<NodeCondition> = vec![];
+        conditions.update_condition_on("Ready", State::True, now);
+        assert_eq!(conditions[0].last_heartbeat_time, Some(Time(now)));
+
+        let now_2 = Utc::now();
+        conditions.update_condition_on("Ready", State::True, now_2);
+        assert_eq!(conditions[0].last_heartbeat_time, Some(Time(now_2)));
+        assert_eq!(conditions[0].last_transition_time, Some(Time(now)));
+    }
+
+    #[test]
+    fn test_transition() {
+        let mut conditions: Vec<JobCondition> = vec![];
+
+        assert_eq!(
+            conditions.update_condition("Ready", State::False.with_reason("Starting")),
+            Some(Transition {
+                r#type: "Ready".into(),
+                from: None,
+                to: State::False,
+                reason: Some("Starting".into()),
+                message: None,
+            })
+        );
+        assert_eq!(
+            conditions.update_condition("Ready", State::False.with_reason("Waiting")),
+            None
+        );
+        assert_eq!(
+            conditions.update_condition("Ready", State::True.with_message("All good")),
+            Some(Transition {
+                r#type: "Ready".into(),
+                from: Some(State::False),
+                to: State::True,
+                reason: None,
+                message: Some("All good".into()),
+            })
+        );
+    }
+
+    #[test]
+    fn test_custom_conditions() {
+        /// Only tracks the latest state, only needs to implement the required method.
+        #[derive(Default)]
+        struct Latest(Option<(String, State)>);
+
+        impl Conditions for Latest {
+            fn update_condition_on<S, D, DT>(
+                &mut self,
+                r#type: S,
+                state: D,
+                _: DT,
+            ) -> Option<Transition>
+            where
+                S: AsRef<str>,
+                D: Into<StateDetails>,
+                DT: Into<DateTime<Utc>>,
+            {
+                let state = state.into().state;
+                self.0 = Some((r#type.as_ref().to_string(), state));
+                None
+            }
+        }
+
+        let mut conditions = Latest::default();
+        conditions.update_condition("Ready", State::True);
+        assert_eq!(conditions.0, Some(("Ready".to_string(), State::True)));
+    }
 }
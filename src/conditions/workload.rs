@@ -0,0 +1,507 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+use super::{Condition, ConditionsExt, State, StateBuilder, StateDetails};
+use k8s_openapi::api::{
+    apps::v1::{DaemonSet, Deployment, StatefulSet},
+    batch::v1::Job,
+};
+
+/// The reason used when the status does not (yet) reflect the current spec.
+pub const REASON_OUTDATED: &str = "Outdated";
+/// The reason used while a rollout is in progress.
+pub const REASON_PROGRESSING: &str = "Progressing";
+/// The reason used when a deployment rollout is stalled.
+pub const REASON_PROGRESS_DEADLINE_EXCEEDED: &str = "ProgressDeadlineExceeded";
+/// The reason used when the workload is ready.
+pub const REASON_AVAILABLE: &str = "Available";
+/// The reason used while a job is running.
+pub const REASON_RUNNING: &str = "Running";
+/// The reason used when a job succeeded.
+pub const REASON_SUCCEEDED: &str = "Succeeded";
+/// The reason used when a job failed, and the job condition doesn't provide one.
+pub const REASON_FAILED: &str = "Failed";
+
+/// Derive a state from the status of a workload resource.
+///
+/// The result can be used with [`Conditions::update_condition`] of the owning resource, e.g. to
+/// reflect the readiness of a child deployment:
+///
+/// ```ignore
+/// status.conditions.update_condition("DeploymentReady", deployment.workload_state());
+/// ```
+///
+/// The logic follows the one of `kubectl rollout status`:
+///
+/// * If the status was not yet updated for the current generation, the state is `Unknown`.
+/// * While the rollout is in progress, the state is `False`, with the reason `Progressing`.
+/// * A deployment which exceeded its progress deadline is `False`, with the reason
+///   `ProgressDeadlineExceeded`.
+/// * Once the rollout is complete, the state is `True`, with the reason `Available`.
+///
+/// A job is `True` once it succeeded, and `False` while it is running or if it failed.
+pub trait WorkloadState {
+    fn workload_state(&self) -> StateDetails;
+}
+
+fn outdated(generation: Option<i64>, observed_generation: Option<i64>) -> Option<StateDetails> {
+    match (generation, observed_generation) {
+        (Some(generation), Some(observed)) if observed >= generation => None,
+        (None, _) => None,
+        _ => Some(
+            State::Unknown
+                .with_reason(REASON_OUTDATED)
+                .with_message("Waiting for the spec update to be observed"),
+        ),
+    }
+}
+
+fn progressing<S: Into<String>>(message: S) -> StateDetails {
+    State::False
+        .with_reason(REASON_PROGRESSING)
+        .with_message(message)
+}
+
+fn available(ready: i32, desired: i32) -> StateDetails {
+    State::True
+        .with_reason(REASON_AVAILABLE)
+        .with_message(format!("{} of {} replicas are available", ready, desired))
+}
+
+fn no_status() -> StateDetails {
+    State::Unknown
+        .with_reason(REASON_OUTDATED)
+        .with_message("Waiting for the status to be reported")
+}
+
+impl WorkloadState for Deployment {
+    fn workload_state(&self) -> StateDetails {
+        let status = match &self.status {
+            Some(status) => status,
+            None => return no_status(),
+        };
+
+        if let Some(state) = outdated(self.metadata.generation, status.observed_generation) {
+            return state;
+        }
+
+        if let Some(condition) = status.conditions.get_condition("Progressing") {
+            if condition.reason() == Some(REASON_PROGRESS_DEADLINE_EXCEEDED) {
+                return State::False
+                    .with_reason(REASON_PROGRESS_DEADLINE_EXCEEDED)
+                    .with_message_opt(condition.message().map(ToString::to_string));
+            }
+        }
+
+        let desired = self
+            .spec
+            .as_ref()
+            .and_then(|spec| spec.replicas)
+            .unwrap_or(1);
+        let replicas = status.replicas.unwrap_or_default();
+        let updated = status.updated_replicas.unwrap_or_default();
+        let available_replicas = status.available_replicas.unwrap_or_default();
+
+        if updated < desired {
+            progressing(format!(
+                "{} of {} new replicas have been updated",
+                updated, desired
+            ))
+        } else if replicas > updated {
+            progressing(format!(
+                "{} old replicas are pending termination",
+                replicas - updated
+            ))
+        } else if available_replicas < updated {
+            progressing(format!(
+                "{} of {} updated replicas are available",
+                available_replicas, updated
+            ))
+        } else {
+            available(available_replicas, desired)
+        }
+    }
+}
+
+impl WorkloadState for StatefulSet {
+    fn workload_state(&self) -> StateDetails {
+        let status = match &self.status {
+            Some(status) => status,
+            None => return no_status(),
+        };
+
+        if let Some(state) = outdated(self.metadata.generation, status.observed_generation) {
+            return state;
+        }
+
+        let spec = self.spec.as_ref();
+        let desired = spec.and_then(|spec| spec.replicas).unwrap_or(1);
+        let ready = status.ready_replicas.unwrap_or_default();
+        let updated = status.updated_replicas.unwrap_or_default();
+
+        if ready < desired {
+            return progressing(format!("{} of {} replicas are ready", ready, desired));
+        }
+
+        let strategy = spec.and_then(|spec| spec.update_strategy.as_ref());
+        if strategy.and_then(|s| s.type_.as_deref()) == Some("OnDelete") {
+            // there is no rollout to track
+            return available(ready, desired);
+        }
+
+        let partition = strategy
+            .and_then(|s| s.rolling_update.as_ref())
+            .and_then(|r| r.partition)
+            .unwrap_or_default();
+
+        if partition > 0 {
+            let expected = (desired - partition).max(0);
+            if updated < expected {
+                return progressing(format!(
+                    "{} of {} replicas of the partitioned rollout have been updated",
+                    updated, expected
+                ));
+            }
+        } else if status.update_revision != status.current_revision {
+            return progressing(format!(
+                "{} of {} replicas have been updated",
+                updated, desired
+            ));
+        }
+
+        available(ready, desired)
+    }
+}
+
+impl WorkloadState for DaemonSet {
+    fn workload_state(&self) -> StateDetails {
+        let status = match &self.status {
+            Some(status) => status,
+            None => return no_status(),
+        };
+
+        if let Some(state) = outdated(self.metadata.generation, status.observed_generation) {
+            return state;
+        }
+
+        let desired = status.desired_number_scheduled;
+        let updated = status.updated_number_scheduled.unwrap_or_default();
+        let available_pods = status.number_available.unwrap_or_default();
+
+        if updated < desired {
+            progressing(format!(
+                "{} of {} scheduled pods have been updated",
+                updated, desired
+            ))
+        } else if available_pods < desired {
+            progressing(format!(
+                "{} of {} scheduled pods are available",
+                available_pods, desired
+            ))
+        } else {
+            available(available_pods, desired)
+        }
+    }
+}
+
+impl WorkloadState for Job {
+    fn workload_state(&self) -> StateDetails {
+        let status = match &self.status {
+            Some(status) => status,
+            None => return no_status(),
+        };
+
+        if status.conditions.is_true("Complete") {
+            return State::True.with_reason(REASON_SUCCEEDED);
+        }
+
+        if let Some(failed) = status
+            .conditions
+            .get_condition("Failed")
+            .filter(|c| c.state() == State::True)
+        {
+            return State::False
+                .with_reason(
+                    failed
+                        .reason()
+                        .filter(|r| !r.is_empty())
+                        .unwrap_or(REASON_FAILED),
+                )
+                .with_message_opt(failed.message().map(ToString::to_string));
+        }
+
+        State::False
+            .with_reason(REASON_RUNNING)
+            .with_message(format!(
+                "{} active, {} succeeded, {} failed",
+                status.active.unwrap_or_default(),
+                status.succeeded.unwrap_or_default(),
+                status.failed.unwrap_or_default()
+            ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use k8s_openapi::{
+        api::{
+            apps::v1::{
+                DaemonSetStatus, DeploymentCondition, DeploymentSpec, DeploymentStatus,
+                RollingUpdateStatefulSetStrategy, StatefulSetSpec, StatefulSetStatus,
+                StatefulSetUpdateStrategy,
+            },
+            batch::v1::{JobCondition, JobStatus},
+        },
+        apimachinery::pkg::apis::meta::v1::ObjectMeta,
+    };
+
+    fn meta(generation: i64) -> ObjectMeta {
+        ObjectMeta {
+            generation: Some(generation),
+            ..Default::default()
+        }
+    }
+
+    fn deployment(replicas: i32, status: DeploymentStatus) -> Deployment {
+        Deployment {
+            metadata: meta(2),
+            spec: Some(DeploymentSpec {
+                replicas: Some(replicas),
+                ..Default::default()
+            }),
+            status: Some(DeploymentStatus {
+                observed_generation: Some(2),
+                ..status
+            }),
+        }
+    }
+
+    #[test]
+    fn test_deployment() {
+        let mut d = deployment(3, Default::default());
+        d.status = None;
+        assert_eq!(d.workload_state().state, State::Unknown);
+
+        let mut d = deployment(3, Default::default());
+        d.status.as_mut().unwrap().observed_generation = Some(1);
+        assert_eq!(d.workload_state().reason.as_deref(), Some(REASON_OUTDATED));
+
+        let d = deployment(
+            3,
+            DeploymentStatus {
+                replicas: Some(3),
+                updated_replicas: Some(1),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            d.workload_state(),
+            progressing("1 of 3 new replicas have been updated")
+        );
+
+        let d = deployment(
+            3,
+            DeploymentStatus {
+                replicas: Some(4),
+                updated_replicas: Some(3),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            d.workload_state(),
+            progressing("1 old replicas are pending termination")
+        );
+
+        let d = deployment(
+            3,
+            DeploymentStatus {
+                replicas: Some(3),
+                updated_replicas: Some(3),
+                available_replicas: Some(2),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            d.workload_state(),
+            progressing("2 of 3 updated replicas are available")
+        );
+
+        let d = deployment(
+            3,
+            DeploymentStatus {
+                replicas: Some(3),
+                updated_replicas: Some(3),
+                available_replicas: Some(3),
+                ..Default::default()
+            },
+        );
+        assert_eq!(d.workload_state(), available(3, 3));
+
+        let d = deployment(
+            3,
+            DeploymentStatus {
+                conditions: Some(vec![DeploymentCondition {
+                    type_: "Progressing".into(),
+                    status: "False".into(),
+                    reason: Some("ProgressDeadlineExceeded".into()),
+                    message: Some("Deadline exceeded".into()),
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            d.workload_state(),
+            State::False
+                .with_reason(REASON_PROGRESS_DEADLINE_EXCEEDED)
+                .with_message("Deadline exceeded")
+        );
+    }
+
+    #[test]
+    fn test_stateful_set() {
+        let stateful_set = |partition: Option<i32>, status: StatefulSetStatus| StatefulSet {
+            metadata: meta(1),
+            spec: Some(StatefulSetSpec {
+                replicas: Some(3),
+                update_strategy: Some(StatefulSetUpdateStrategy {
+                    type_: Some("RollingUpdate".into()),
+                    rolling_update: Some(RollingUpdateStatefulSetStrategy { partition }),
+                }),
+                ..Default::default()
+            }),
+            status: Some(StatefulSetStatus {
+                observed_generation: Some(1),
+                replicas: 3,
+                ..status
+            }),
+        };
+
+        let s = stateful_set(
+            None,
+            StatefulSetStatus {
+                ready_replicas: Some(2),
+                ..Default::default()
+            },
+        );
+        assert_eq!(s.workload_state(), progressing("2 of 3 replicas are ready"));
+
+        let s = stateful_set(
+            None,
+            StatefulSetStatus {
+                ready_replicas: Some(3),
+                updated_replicas: Some(1),
+                current_revision: Some("a".into()),
+                update_revision: Some("b".into()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            s.workload_state(),
+            progressing("1 of 3 replicas have been updated")
+        );
+
+        let s = stateful_set(
+            Some(2),
+            StatefulSetStatus {
+                ready_replicas: Some(3),
+                updated_replicas: Some(1),
+                current_revision: Some("a".into()),
+                update_revision: Some("b".into()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(s.workload_state(), available(3, 3));
+
+        let s = stateful_set(
+            None,
+            StatefulSetStatus {
+                ready_replicas: Some(3),
+                updated_replicas: Some(3),
+                current_revision: Some("b".into()),
+                update_revision: Some("b".into()),
+                ..Default::default()
+            },
+        );
+        assert_eq!(s.workload_state(), available(3, 3));
+    }
+
+    #[test]
+    fn test_daemon_set() {
+        let daemon_set = |updated, available| DaemonSet {
+            metadata: meta(1),
+            spec: None,
+            status: Some(DaemonSetStatus {
+                observed_generation: Some(1),
+                desired_number_scheduled: 2,
+                updated_number_scheduled: Some(updated),
+                number_available: Some(available),
+                ..Default::default()
+            }),
+        };
+
+        assert_eq!(
+            daemon_set(1, 1).workload_state(),
+            progressing("1 of 2 scheduled pods have been updated")
+        );
+        assert_eq!(
+            daemon_set(2, 1).workload_state(),
+            progressing("1 of 2 scheduled pods are available")
+        );
+        assert_eq!(daemon_set(2, 2).workload_state(), available(2, 2));
+    }
+
+    #[test]
+    fn test_job() {
+        let job = |conditions: Vec<JobCondition>| Job {
+            metadata: meta(1),
+            spec: None,
+            status: Some(JobStatus {
+                active: Some(1),
+                conditions: Some(conditions),
+                ..Default::default()
+            }),
+        };
+
+        assert_eq!(
+            job(vec![]).workload_state(),
+            State::False
+                .with_reason(REASON_RUNNING)
+                .with_message("1 active, 0 succeeded, 0 failed")
+        );
+        assert_eq!(
+            job(vec![JobCondition {
+                type_: "Complete".into(),
+                status: "True".into(),
+                ..Default::default()
+            }])
+            .workload_state(),
+            State::True.with_reason(REASON_SUCCEEDED)
+        );
+        assert_eq!(
+            job(vec![JobCondition {
+                type_: "Failed".into(),
+                status: "True".into(),
+                reason: Some("BackoffLimitExceeded".into()),
+                message: Some("Job has reached the specified backoff limit".into()),
+                ..Default::default()
+            }])
+            .workload_state(),
+            State::False
+                .with_reason("BackoffLimitExceeded")
+                .with_message("Job has reached the specified backoff limit")
+        );
+    }
+}
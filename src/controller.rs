@@ -0,0 +1,548 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+//! An opinionated controller runtime, built on top of [`kube::runtime::Controller`].
+//!
+//! The controller watches the primary resource (and optionally owned resources), and calls a
+//! [`Reconciler`] for each change. The underlying runtime ensures that the same resource is never
+//! reconciled concurrently.
+//!
+//! After each reconciliation, the status of the primary resource is updated: the "ready" condition
+//! reflects the outcome of the reconciliation, and the observed generation is set to the generation
+//! which was reconciled. Failed reconciliations are re-scheduled with an exponential backoff.
+//!
+//! Note: as status updates trigger a new reconciliation, the conditions of the status should not
+//! track the last probe time, otherwise the status changes with every reconciliation.
+//!
+//! Optionally, changes of the "ready" condition can be recorded as Kubernetes events, using a
+//! [`Recorder`].
+
+use crate::{
+    conditions::{Conditions, HasConditions, State, StateBuilder, StateDetails},
+    process::{update_status_with, CreateOrUpdateParams, RetryPolicy},
+    recorder::Recorder,
+};
+use async_trait::async_trait;
+use futures::StreamExt;
+use k8s_openapi::{ClusterResourceScope, NamespaceResourceScope};
+use kube::{
+    api::ListParams,
+    core::object::HasStatus,
+    runtime::{
+        controller::{self, Action},
+        reflector::ObjectRef,
+        watcher,
+    },
+    Api, Client, Resource, ResourceExt,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    collections::HashMap,
+    fmt::Debug,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Reconcile a resource.
+#[async_trait]
+pub trait Reconciler<K>: Send + Sync + 'static {
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Reconcile the resource.
+    ///
+    /// The resource is the latest known state of the primary resource.
+    async fn reconcile(&self, resource: Arc<K>) -> Result<Reconciled, Self::Error>;
+}
+
+/// The outcome of a successful reconciliation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reconciled {
+    /// The state of the ready condition.
+    pub state: StateDetails,
+    /// Re-schedule the reconciliation after this duration, even if nothing changed.
+    pub requeue_after: Option<Duration>,
+}
+
+impl Default for Reconciled {
+    fn default() -> Self {
+        Self {
+            state: State::True.into(),
+            requeue_after: None,
+        }
+    }
+}
+
+impl Reconciled {
+    /// The resource is ready, wait for the next change.
+    pub fn done() -> Self {
+        Default::default()
+    }
+
+    /// The resource is ready, but should be reconciled again after the provided duration.
+    pub fn requeue_after(duration: Duration) -> Self {
+        Self {
+            requeue_after: Some(duration),
+            ..Default::default()
+        }
+    }
+
+    /// Set the state of the ready condition.
+    pub fn with_state<S>(mut self, state: S) -> Self
+    where
+        S: Into<StateDetails>,
+    {
+        self.state = state.into();
+        self
+    }
+}
+
+/// Access to the observed generation of a status.
+pub trait ObservedGeneration {
+    fn set_observed_generation(&mut self, observed_generation: Option<i64>);
+}
+
+/// The configuration of a [`Controller`].
+#[derive(Clone, Debug)]
+pub struct ControllerConfig {
+    /// The type of the condition reflecting the outcome of the reconciliation.
+    pub condition_type: String,
+    /// The reason used for the condition when the reconciliation failed.
+    pub failure_reason: String,
+    /// The backoff for failed reconciliations. The maximum number of attempts is ignored, as
+    /// failed reconciliations are always re-scheduled.
+    pub backoff: RetryPolicy,
+    /// The parameters used when updating the status.
+    pub status: CreateOrUpdateParams,
+}
+
+impl Default for ControllerConfig {
+    fn default() -> Self {
+        Self {
+            condition_type: "Ready".into(),
+            failure_reason: "ReconcileFailed".into(),
+            backoff: RetryPolicy {
+                initial_backoff: Duration::from_secs(1),
+                max_backoff: Duration::from_secs(300),
+                ..Default::default()
+            },
+            status: Default::default(),
+        }
+    }
+}
+
+/// A controller, running a [`Reconciler`] for a primary resource.
+pub struct Controller<K, R>
+where
+    K: Resource<DynamicType = ()> + Clone + Debug + DeserializeOwned + Send + Sync + 'static,
+{
+    client: Client,
+    api_for: ApiFor<K>,
+    controller: controller::Controller<K>,
+    reconciler: R,
+    config: ControllerConfig,
+    recorder: Option<Recorder>,
+}
+
+/// Create an API for a resource, located in an optional namespace.
+type ApiFor<K> = fn(Client, Option<&str>) -> Api<K>;
+
+impl<K, R> Controller<K, R>
+where
+    K: Resource<DynamicType = ()>
+        + HasStatus
+        + Clone
+        + Debug
+        + DeserializeOwned
+        + Serialize
+        + Send
+        + Sync
+        + 'static,
+    K::Status: HasConditions + ObservedGeneration + Clone + Default + PartialEq + Send,
+    R: Reconciler<K>,
+{
+    /// Create a new controller for a namespaced resource, watching the provided API.
+    pub fn new(api: Api<K>, lp: ListParams, reconciler: R) -> Self
+    where
+        K: Resource<Scope = NamespaceResourceScope>,
+    {
+        Self::new_with(api, lp, reconciler, |client, namespace| match namespace {
+            Some(namespace) => Api::namespaced(client, namespace),
+            None => Api::all(client),
+        })
+    }
+
+    /// Create a new controller for a cluster scoped resource, watching the provided API.
+    pub fn new_cluster(api: Api<K>, lp: ListParams, reconciler: R) -> Self
+    where
+        K: Resource<Scope = ClusterResourceScope>,
+    {
+        Self::new_with(api, lp, reconciler, |client, _| Api::all(client))
+    }
+
+    fn new_with(api: Api<K>, lp: ListParams, reconciler: R, api_for: ApiFor<K>) -> Self {
+        Self {
+            client: api.clone().into(),
+            api_for,
+            controller: controller::Controller::new(api, lp),
+            reconciler,
+            config: Default::default(),
+            recorder: None,
+        }
+    }
+
+    /// Set the configuration of the controller.
+    pub fn with_config(mut self, config: ControllerConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Record an event for each transition of the ready condition.
+    pub fn with_recorder(mut self, recorder: Recorder) -> Self {
+        self.recorder = Some(recorder);
+        self
+    }
+
+    /// Also trigger reconciliations when a resource owned by the primary resource changes.
+    pub fn owns<C>(mut self, api: Api<C>, lp: ListParams) -> Self
+    where
+        C: Resource<DynamicType = ()> + Clone + Debug + DeserializeOwned + Send + 'static,
+    {
+        self.controller = self.controller.owns(api, lp);
+        self
+    }
+
+    /// Run the controller, until the watch of the primary resource ends.
+    pub async fn run(self) {
+        let context = Arc::new(Context {
+            client: self.client,
+            api_for: self.api_for,
+            reconciler: self.reconciler,
+            config: self.config,
+            recorder: self.recorder,
+            failures: Default::default(),
+        });
+
+        self.controller
+            .run(
+                |resource, ctx| async move { ctx.reconcile(resource).await },
+                |resource, err, ctx| ctx.error_policy(resource, err),
+                context.clone(),
+            )
+            .for_each(|result| {
+                context.completed(result);
+                futures::future::ready(())
+            })
+            .await;
+    }
+}
+
+/// Counting the consecutive failures per resource.
+#[derive(Debug, Default)]
+struct Failures(Mutex<HashMap<(Option<String>, String), usize>>);
+
+impl Failures {
+    /// Record a failure, returning the number of consecutive failures.
+    fn fail(&self, key: (Option<String>, String)) -> usize {
+        let mut failures = self.0.lock().unwrap();
+        let n = failures.entry(key).or_default();
+        *n += 1;
+        *n
+    }
+
+    fn reset(&self, key: &(Option<String>, String)) {
+        self.0.lock().unwrap().remove(key);
+    }
+}
+
+struct Context<K, R> {
+    client: Client,
+    api_for: ApiFor<K>,
+    reconciler: R,
+    config: ControllerConfig,
+    recorder: Option<Recorder>,
+    failures: Failures,
+}
+
+fn key<K: Resource>(resource: &K) -> (Option<String>, String) {
+    (resource.namespace(), resource.name_any())
+}
+
+impl<K, R> Context<K, R> {
+    async fn reconcile(&self, resource: Arc<K>) -> Result<Action, kube::Error>
+    where
+        K: Resource<DynamicType = ()>
+            + HasStatus
+            + Clone
+            + Debug
+            + DeserializeOwned
+            + Serialize
+            + Send
+            + Sync
+            + 'static,
+        K::Status: HasConditions + ObservedGeneration + Clone + Default + PartialEq + Send,
+        R: Reconciler<K>,
+    {
+        let key = key(resource.as_ref());
+        let generation = resource.meta().generation;
+
+        let (state, action) = match self.reconciler.reconcile(resource.clone()).await {
+            Ok(reconciled) => {
+                self.failures.reset(&key);
+                let action = match reconciled.requeue_after {
+                    Some(duration) => Action::requeue(duration),
+                    None => Action::await_change(),
+                };
+                (reconciled.state, action)
+            }
+            Err(err) => {
+                let failures = self.failures.fail(key.clone());
+                log::info!(
+                    "Failed to reconcile {:?} (attempt {}): {}",
+                    key,
+                    failures,
+                    err
+                );
+                let state = State::False
+                    .with_reason(self.config.failure_reason.clone())
+                    .with_message(err.to_string());
+                (
+                    state,
+                    Action::requeue(self.config.backoff.backoff_with_jitter(failures)),
+                )
+            }
+        };
+
+        let state = state.with_observed(generation);
+        let api = (self.api_for)(self.client.clone(), key.0.as_deref());
+
+        let mut transition = None;
+        let result = update_status_with(&api, &key.1, &self.config.status, |status| {
+            status.set_observed_generation(generation);
+            transition = status
+                .conditions_mut()
+                .update_condition(&self.config.condition_type, state.clone());
+            Ok::<_, kube::Error>(())
+        })
+        .await;
+
+        match result {
+            Ok(_) => {
+                if let (Some(recorder), Some(transition)) = (&self.recorder, transition) {
+                    if let Err(err) = recorder
+                        .record_transition(resource.as_ref(), &transition)
+                        .await
+                    {
+                        log::info!("Failed to record event for {:?}: {}", key, err);
+                    }
+                }
+                Ok(action)
+            }
+            // the resource is already gone, nothing to update
+            Err(kube::Error::Api(err)) if err.code == 404 => {
+                self.failures.reset(&key);
+                Ok(Action::await_change())
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn error_policy(&self, resource: Arc<K>, err: &kube::Error) -> Action
+    where
+        K: Resource,
+    {
+        let key = key(resource.as_ref());
+        let failures = self.failures.fail(key.clone());
+        log::info!(
+            "Failed to update status of {:?} (attempt {}): {}",
+            key,
+            failures,
+            err
+        );
+        Action::requeue(self.config.backoff.backoff_with_jitter(failures))
+    }
+
+    /// Handle the result of a reconciliation, as reported by the controller runtime.
+    fn completed(&self, result: Result<(ObjectRef<K>, Action), ControllerError>)
+    where
+        K: Resource,
+    {
+        match result {
+            Ok((resource, _)) => log::debug!("Reconciled: {}", resource),
+            // the resource got deleted, forget about its failures
+            Err(controller::Error::ObjectNotFound(resource)) => {
+                log::debug!("Resource is gone: {}", resource);
+                self.failures.reset(&(resource.namespace, resource.name));
+            }
+            Err(err) => log::info!("Failed to reconcile: {}", err),
+        }
+    }
+}
+
+/// The error reported by the controller runtime.
+type ControllerError = controller::Error<kube::Error, watcher::Error>;
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::{conditions::ConditionsExt, testing::FakeServer};
+    use k8s_openapi::api::batch::v1::JobCondition;
+    use kube::{api::PostParams, CustomResource};
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(CustomResource, Clone, Debug, Default, Deserialize, Serialize)]
+    #[kube(
+        group = "example.com",
+        version = "v1",
+        kind = "Example",
+        namespaced,
+        status = "ExampleStatus",
+        schema = "disabled"
+    )]
+    pub struct ExampleSpec {
+        fail: bool,
+    }
+
+    #[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+    pub struct ExampleStatus {
+        observed_generation: Option<i64>,
+        conditions: Option<Vec<JobCondition>>,
+    }
+
+    impl HasConditions for ExampleStatus {
+        type Conditions = Option<Vec<JobCondition>>;
+
+        fn conditions_mut(&mut self) -> &mut Self::Conditions {
+            &mut self.conditions
+        }
+    }
+
+    impl ObservedGeneration for ExampleStatus {
+        fn set_observed_generation(&mut self, observed_generation: Option<i64>) {
+            self.observed_generation = observed_generation;
+        }
+    }
+
+    struct ExampleReconciler;
+
+    #[async_trait]
+    impl Reconciler<Example> for ExampleReconciler {
+        type Error = std::fmt::Error;
+
+        async fn reconcile(&self, resource: Arc<Example>) -> Result<Reconciled, Self::Error> {
+            match resource.spec.fail {
+                true => Err(std::fmt::Error),
+                false => Ok(Reconciled::done()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reconcile() {
+        let server = FakeServer::new();
+        let api = Api::<Example>::namespaced(server.client(), "default");
+        let context = Context {
+            client: server.client(),
+            api_for: |client, namespace| Api::namespaced(client, namespace.unwrap()),
+            reconciler: ExampleReconciler,
+            config: ControllerConfig::default(),
+            recorder: None,
+            failures: Default::default(),
+        };
+        let key = (Some("default".to_string()), "foo".to_string());
+
+        let reconcile = |fail: bool| {
+            let api = api.clone();
+            let context = &context;
+            let key = &key;
+            async move {
+                let mut example = api.get("foo").await.unwrap();
+                example.spec.fail = fail;
+                let example = api
+                    .replace("foo", &PostParams::default(), &example)
+                    .await
+                    .unwrap();
+                context.reconcile(Arc::new(example)).await.unwrap();
+                let ready = api
+                    .get("foo")
+                    .await
+                    .unwrap()
+                    .status
+                    .unwrap()
+                    .conditions
+                    .get_condition("Ready")
+                    .map(|condition| condition.status.clone());
+                (ready, context.failures.0.lock().unwrap().get(key).copied())
+            }
+        };
+
+        let mut example = Example::new("foo", ExampleSpec::default());
+        example.metadata.namespace = Some("default".into());
+        server.insert(example);
+
+        // failures are counted, and reset by a successful reconciliation
+
+        assert_eq!(reconcile(true).await, (Some("False".into()), Some(1)));
+        assert_eq!(reconcile(true).await, (Some("False".into()), Some(2)));
+        assert_eq!(reconcile(false).await, (Some("True".into()), None));
+        assert_eq!(reconcile(true).await, (Some("False".into()), Some(1)));
+
+        // the failures of deleted resources are removed
+
+        let example = api.get("foo").await.unwrap();
+        api.delete("foo", &Default::default()).await.unwrap();
+        context.reconcile(Arc::new(example)).await.unwrap();
+        assert!(context.failures.0.lock().unwrap().is_empty());
+
+        context.failures.fail(key.clone());
+        context.completed(Err(controller::Error::ObjectNotFound(
+            ObjectRef::<Example>::new("foo").within("default").erase(),
+        )));
+        assert!(context.failures.0.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_failures() {
+        let failures = Failures::default();
+        let foo = (Some("ns".to_string()), "foo".to_string());
+        let bar = (None, "bar".to_string());
+
+        assert_eq!(failures.fail(foo.clone()), 1);
+        assert_eq!(failures.fail(foo.clone()), 2);
+        assert_eq!(failures.fail(bar.clone()), 1);
+
+        failures.reset(&foo);
+
+        assert_eq!(failures.fail(foo), 1);
+        assert_eq!(failures.fail(bar), 2);
+    }
+
+    #[test]
+    fn test_reconciled() {
+        assert_eq!(
+            Reconciled::done(),
+            Reconciled {
+                state: State::True.into(),
+                requeue_after: None
+            }
+        );
+        assert_eq!(
+            Reconciled::requeue_after(Duration::from_secs(10))
+                .with_state(State::False.with_reason("Waiting")),
+            Reconciled {
+                state: State::False.with_reason("Waiting"),
+                requeue_after: Some(Duration::from_secs(10)),
+            }
+        );
+    }
+}
@@ -11,15 +11,108 @@
  * SPDX-License-Identifier: EPL-2.0
  */
 
+use crate::{
+    process::RetryPolicy,
+    selectors::{FieldSelector, ToSelector, WithSelectors},
+};
 use async_trait::async_trait;
 use either::Either::{Left, Right};
-use futures::future::FutureExt;
+use futures::{future::FutureExt, StreamExt, TryStreamExt};
 use kube::{
-    api::{DeleteParams, Preconditions},
-    Api, Error, Resource,
+    api::{DeleteParams, ListParams, Preconditions, PropagationPolicy, WatchEvent},
+    Api, Error, Resource, ResourceExt,
 };
 use serde::de::DeserializeOwned;
-use std::fmt::Debug;
+use std::{
+    fmt::{Debug, Display, Formatter},
+    time::Duration,
+};
+
+/// An error deleting resources, or waiting for their deletion.
+#[derive(Debug)]
+pub enum DeleteError {
+    /// A request to the API failed.
+    Api(kube::Error),
+    /// The selector is empty, and would match all resources.
+    EmptySelector,
+    /// The resource was still present when the timeout expired.
+    Timeout {
+        /// The finalizers which are blocking the deletion, may be empty.
+        finalizers: Vec<String>,
+    },
+}
+
+impl Display for DeleteError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Api(err) => write!(f, "API error: {}", err),
+            Self::EmptySelector => write!(f, "Refusing to delete using an empty selector"),
+            Self::Timeout { finalizers } if finalizers.is_empty() => {
+                write!(f, "Timeout waiting for deletion")
+            }
+            Self::Timeout { finalizers } => write!(
+                f,
+                "Timeout waiting for deletion, blocked by finalizers: {}",
+                finalizers.join(", ")
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DeleteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Api(err) => Some(err),
+            Self::EmptySelector | Self::Timeout { .. } => None,
+        }
+    }
+}
+
+impl From<kube::Error> for DeleteError {
+    fn from(err: kube::Error) -> Self {
+        Self::Api(err)
+    }
+}
+
+/// The deletion of a single resource, see [`DeleteExt::delete_all_matching`].
+#[derive(Debug)]
+pub struct Deletion {
+    pub namespace: Option<String>,
+    pub name: String,
+    pub outcome: DeletionOutcome,
+}
+
+/// The outcome of deleting a single resource.
+#[derive(Debug)]
+pub enum DeletionOutcome {
+    /// The resource is gone.
+    Deleted,
+    /// The deletion is pending, e.g. because of finalizers.
+    Pending,
+    /// The resource was modified in the meantime, and was not deleted.
+    Conflict,
+    /// Deleting the resource failed.
+    Failed(kube::Error),
+}
+
+impl DeletionOutcome {
+    fn of<K: Resource>(resource: &K) -> Self {
+        match resource.finalizers().is_empty() {
+            true => Self::Deleted,
+            false => Self::Pending,
+        }
+    }
+}
+
+impl Deletion {
+    fn new<K: Resource>(resource: &K, outcome: DeletionOutcome) -> Self {
+        Self {
+            namespace: resource.namespace(),
+            name: resource.name_any(),
+            outcome,
+        }
+    }
+}
 
 #[async_trait]
 pub trait Delete<R: Send> {
@@ -91,3 +184,437 @@ where
         }
     }
 }
+
+/// Deleting resources, and waiting for their deletion.
+///
+/// This extends [`Delete`], and is implemented for all [`Api`]s.
+#[async_trait]
+pub trait DeleteExt<R: Send>: Delete<R> {
+    /// Delete a resource, and wait until it is gone.
+    ///
+    /// If the deletion is pending, the resource is watched until it disappears. If it is still
+    /// present after the timeout, a [`DeleteError::Timeout`] is returned, carrying the finalizers
+    /// which block the deletion. Dropping the returned future cancels waiting, but not the
+    /// deletion itself.
+    async fn delete_and_wait<S>(
+        &self,
+        name: S,
+        dp: &DeleteParams,
+        timeout: Duration,
+    ) -> Result<(), DeleteError>
+    where
+        S: AsRef<str> + Send + Sync;
+
+    /// Delete all resources matching a label selector.
+    ///
+    /// This uses a single `deletecollection` request. If the resource type doesn't support this,
+    /// the matching resources are listed and deleted one by one, guarded by their UID and resource
+    /// version. Resources which got modified in the meantime are reported as
+    /// [`DeletionOutcome::Conflict`], and are not deleted.
+    ///
+    /// Returns the outcome for each resource. Only failing to list or delete the collection is
+    /// reported as an error. An empty selector is rejected with [`DeleteError::EmptySelector`],
+    /// before sending any request, as it would delete all resources.
+    async fn delete_all_matching<S, P>(
+        &self,
+        selector: &S,
+        propagation: P,
+    ) -> Result<Vec<Deletion>, DeleteError>
+    where
+        S: ToSelector + ?Sized + Sync,
+        P: Into<Option<PropagationPolicy>> + Send;
+}
+
+#[async_trait]
+impl<K> DeleteExt<K> for Api<K>
+where
+    K: Resource + Clone + DeserializeOwned + Send + Debug,
+{
+    async fn delete_and_wait<S>(
+        &self,
+        name: S,
+        dp: &DeleteParams,
+        timeout: Duration,
+    ) -> Result<(), DeleteError>
+    where
+        S: AsRef<str> + Send + Sync,
+    {
+        let name = name.as_ref();
+
+        if self.delete_optionally(name, dp).await? {
+            return Ok(());
+        }
+
+        let mut finalizers = Vec::new();
+        // back off when the watch keeps failing, e.g. when lacking permissions
+        let backoff = RetryPolicy::default();
+        let mut failures = 0;
+
+        let wait = async {
+            loop {
+                if failures > 0 {
+                    backoff.wait(failures).await;
+                }
+
+                // fetch the current state, and watch for changes from there on
+                let resource = match self.get(name).await {
+                    Err(Error::Api(cause)) if cause.reason == "NotFound" => return Ok(()),
+                    result => result?,
+                };
+                finalizers = resource.meta().finalizers.clone().unwrap_or_default();
+
+                let lp = ListParams::default()
+                    .with_fields(&FieldSelector::new().equal("metadata.name", name));
+                let version = resource.meta().resource_version.clone().unwrap_or_default();
+                let mut events = self.watch(&lp, &version).await?.boxed();
+
+                while let Some(event) = events.try_next().await? {
+                    match event {
+                        WatchEvent::Deleted(_) => return Ok(()),
+                        WatchEvent::Added(resource) | WatchEvent::Modified(resource) => {
+                            finalizers = resource.meta().finalizers.clone().unwrap_or_default();
+                        }
+                        WatchEvent::Bookmark(_) => {}
+                        WatchEvent::Error(err) => {
+                            // e.g. the resource version is too old, start over
+                            log::debug!("Failed to watch for deletion of {}: {}", name, err);
+                            failures += 1;
+                            break;
+                        }
+                    }
+                }
+            }
+        };
+
+        match tokio::time::timeout(timeout, wait).await {
+            Ok(result) => result.map_err(DeleteError::Api),
+            Err(_) => Err(DeleteError::Timeout { finalizers }),
+        }
+    }
+
+    async fn delete_all_matching<S, P>(
+        &self,
+        selector: &S,
+        propagation: P,
+    ) -> Result<Vec<Deletion>, DeleteError>
+    where
+        S: ToSelector + ?Sized + Sync,
+        P: Into<Option<PropagationPolicy>> + Send,
+    {
+        let selector = selector.to_selector();
+        if selector.is_empty() {
+            return Err(DeleteError::EmptySelector);
+        }
+
+        let lp = ListParams::default().labels(&selector);
+        let dp = DeleteParams {
+            propagation_policy: propagation.into(),
+            ..Default::default()
+        };
+
+        // the response of deleting the collection may not contain the deleted resources
+        let listed = self.list(&lp).await?;
+
+        match self.delete_collection(&dp, &lp).await {
+            Ok(Left(list)) => Ok(list
+                .iter()
+                .map(|resource| Deletion::new(resource, DeletionOutcome::of(resource)))
+                .collect()),
+            Ok(Right(status)) => {
+                log::debug!("Deleted collection: {:?}", status);
+                Ok(listed
+                    .iter()
+                    .map(|resource| Deletion::new(resource, DeletionOutcome::of(resource)))
+                    .collect())
+            }
+            Err(Error::Api(cause)) if cause.code == 405 => {
+                log::debug!("Unable to delete collection, deleting one by one");
+                let mut result = Vec::new();
+                for resource in listed {
+                    let dp = DeleteParams {
+                        preconditions: Some(Preconditions {
+                            resource_version: resource.meta().resource_version.clone(),
+                            uid: resource.meta().uid.clone(),
+                        }),
+                        ..dp.clone()
+                    };
+                    let outcome = match self.delete(&resource.name_any(), &dp).await {
+                        Ok(Left(resource)) => DeletionOutcome::of(&resource),
+                        Ok(Right(_)) => DeletionOutcome::Deleted,
+                        Err(Error::Api(cause)) if cause.code == 404 => DeletionOutcome::Deleted,
+                        Err(Error::Api(cause)) if cause.code == 409 => DeletionOutcome::Conflict,
+                        Err(err) => DeletionOutcome::Failed(err),
+                    };
+                    result.push(Deletion::new(&resource, outcome));
+                }
+                Ok(result)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::{
+        selectors::Selector,
+        testing::{config_map, FakeServer},
+    };
+    use k8s_openapi::api::core::v1::ConfigMap;
+    use kube::api::{Patch, PatchParams};
+    use serde_json::json;
+
+    #[tokio::test]
+    async fn test_delete_optionally() {
+        let server = FakeServer::new();
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+
+        server.insert(config_map("foo").build());
+        server.insert(config_map("bar").finalizer("example.com/test").build());
+
+        assert!(api
+            .delete_optionally("foo", &Default::default())
+            .await
+            .unwrap());
+        assert!(server.get::<ConfigMap>(Some("default"), "foo").is_none());
+
+        // already gone
+        assert!(api
+            .delete_optionally("foo", &Default::default())
+            .await
+            .unwrap());
+
+        // blocked by a finalizer
+        assert!(!api
+            .delete_optionally("bar", &Default::default())
+            .await
+            .unwrap());
+        assert!(server
+            .get::<ConfigMap>(Some("default"), "bar")
+            .unwrap()
+            .metadata
+            .deletion_timestamp
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn test_delete_conditionally() {
+        let server = FakeServer::new();
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+
+        // missing
+        assert!(!api
+            .delete_conditionally("foo", |_| Ok::<_, Error>(true))
+            .await
+            .unwrap());
+
+        server.insert(config_map("foo").build());
+
+        // rejected
+        assert!(!api
+            .delete_conditionally("foo", |_| Ok::<_, Error>(false))
+            .await
+            .unwrap());
+        assert!(server.get::<ConfigMap>(Some("default"), "foo").is_some());
+
+        // accepted
+        assert!(api
+            .delete_conditionally("foo", |cm| Ok::<_, Error>(cm.metadata.name.is_some()))
+            .await
+            .unwrap());
+        assert!(server.get::<ConfigMap>(Some("default"), "foo").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_delete_conditionally_modified() {
+        let server = FakeServer::new();
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+
+        server.insert(config_map("foo").build());
+
+        // the resource gets modified between checking and deleting, so the precondition fails
+        let result = api
+            .delete_conditionally("foo", |cm| {
+                server.insert(cm.clone());
+                Ok::<_, Error>(true)
+            })
+            .await;
+
+        assert!(matches!(result, Err(Error::Api(err)) if err.code == 409));
+        assert!(server.get::<ConfigMap>(Some("default"), "foo").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_delete_and_wait() {
+        let server = FakeServer::new();
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+        let timeout = Duration::from_secs(5);
+
+        // missing
+        api.delete_and_wait("foo", &Default::default(), timeout)
+            .await
+            .unwrap();
+
+        // deleted immediately
+        server.insert(config_map("foo").build());
+        api.delete_and_wait("foo", &Default::default(), timeout)
+            .await
+            .unwrap();
+        assert!(server.get::<ConfigMap>(Some("default"), "foo").is_none());
+
+        // deleted once the finalizer is removed
+        server.insert(config_map("foo").finalizer("example.com/test").build());
+        let finalize = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            api.patch(
+                "foo",
+                &PatchParams::default(),
+                &Patch::Merge(json!({"metadata": {"finalizers": null}})),
+            )
+            .await
+        };
+        let dp = DeleteParams::default();
+        let (result, finalized) =
+            futures::join!(api.delete_and_wait("foo", &dp, timeout), finalize);
+        result.unwrap();
+        finalized.unwrap();
+        assert!(server.get::<ConfigMap>(Some("default"), "foo").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_delete_and_wait_timeout() {
+        let server = FakeServer::new();
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+
+        server.insert(config_map("foo").finalizer("example.com/test").build());
+
+        let result = api
+            .delete_and_wait("foo", &Default::default(), Duration::from_millis(50))
+            .await;
+
+        match result {
+            Err(DeleteError::Timeout { finalizers }) => {
+                assert_eq!(finalizers, vec!["example.com/test".to_string()])
+            }
+            other => panic!("Unexpected result: {:?}", other),
+        }
+        assert!(server
+            .get::<ConfigMap>(Some("default"), "foo")
+            .unwrap()
+            .metadata
+            .deletion_timestamp
+            .is_some());
+    }
+
+    fn outcomes(deletions: Vec<Deletion>) -> Vec<(String, String)> {
+        deletions
+            .into_iter()
+            .map(|d| (d.name, format!("{:?}", d.outcome)))
+            .collect()
+    }
+
+    fn setup() -> (FakeServer, Api<ConfigMap>) {
+        let server = FakeServer::new();
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+
+        server.insert(config_map("a").label("app", "foo").build());
+        server.insert(
+            config_map("b")
+                .label("app", "foo")
+                .finalizer("example.com/test")
+                .build(),
+        );
+        server.insert(config_map("c").label("app", "bar").build());
+
+        (server, api)
+    }
+
+    #[tokio::test]
+    async fn test_delete_all_matching() {
+        let (server, api) = setup();
+
+        let selector: Selector = "app=foo".parse().unwrap();
+        let result = api
+            .delete_all_matching(&selector, PropagationPolicy::Background)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            outcomes(result),
+            vec![
+                ("a".to_string(), "Deleted".to_string()),
+                ("b".to_string(), "Pending".to_string())
+            ]
+        );
+        assert_eq!(
+            server
+                .list::<ConfigMap>(Some("default"))
+                .iter()
+                .map(|cm| cm.name_any())
+                .collect::<Vec<_>>(),
+            vec!["b", "c"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_all_matching_fallback() {
+        let (server, api) = setup();
+        server.disable_delete_collection::<ConfigMap>();
+
+        let selector: Selector = "app=foo".parse().unwrap();
+        let result = api.delete_all_matching(&selector, None).await.unwrap();
+
+        assert_eq!(
+            outcomes(result),
+            vec![
+                ("a".to_string(), "Deleted".to_string()),
+                ("b".to_string(), "Pending".to_string())
+            ]
+        );
+        assert_eq!(
+            server
+                .list::<ConfigMap>(Some("default"))
+                .iter()
+                .map(|cm| cm.name_any())
+                .collect::<Vec<_>>(),
+            vec!["b", "c"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_all_matching_status() {
+        let (server, api) = setup();
+        server.status_delete_collection::<ConfigMap>();
+
+        let selector: Selector = "app=foo".parse().unwrap();
+        let result = api.delete_all_matching(&selector, None).await.unwrap();
+
+        assert_eq!(
+            outcomes(result),
+            vec![
+                ("a".to_string(), "Deleted".to_string()),
+                ("b".to_string(), "Pending".to_string())
+            ]
+        );
+        assert_eq!(
+            server
+                .list::<ConfigMap>(Some("default"))
+                .iter()
+                .map(|cm| cm.name_any())
+                .collect::<Vec<_>>(),
+            vec!["b", "c"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_all_matching_empty_selector() {
+        let (server, api) = setup();
+
+        let result = api.delete_all_matching(&Selector::default(), None).await;
+
+        assert!(matches!(result, Err(DeleteError::EmptySelector)));
+        assert_eq!(server.list::<ConfigMap>(Some("default")).len(), 3);
+    }
+}
@@ -0,0 +1,217 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+use crate::install::meta::Finalizers;
+use kube::{
+    api::{Patch, PatchParams},
+    Api, Resource, ResourceExt,
+};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use std::{fmt::Debug, future::Future};
+
+/// The outcome of [`finalize`].
+#[derive(Clone, Debug)]
+pub enum Finalized<K> {
+    /// The resource is active and carries the finalizer, it should be reconciled as usual.
+    Active(K),
+    /// The resource is being deleted, but the cleanup is not yet complete.
+    Pending(K),
+    /// The resource is being deleted, and no longer carries the finalizer.
+    Finalized,
+}
+
+/// Manage a finalizer of a resource.
+///
+/// If the resource is not being deleted, the finalizer gets added (if it is missing). If the
+/// resource is being deleted, and still carries the finalizer, the cleanup gets run. Once the
+/// cleanup reports that it is complete, the finalizer gets removed.
+///
+/// The cleanup returns `true` if the cleanup is complete, and `false` if it is still in progress,
+/// matching the result of [`crate::install::Delete::delete_optionally`]:
+///
+/// ```ignore
+/// let outcome = finalize(&api, resource, "example.com/cleanup", |resource| async move {
+///     deployments.delete_optionally(resource.name_any(), &Default::default()).await
+/// })
+/// .await?;
+/// ```
+///
+/// The finalizers are modified using a patch, which carries the `resourceVersion` of the
+/// resource. So if the resource was modified in the meantime, the operation fails with a conflict,
+/// instead of overwriting finalizers which got changed by someone else.
+pub async fn finalize<K, S, F, Fut, E>(
+    api: &Api<K>,
+    resource: K,
+    finalizer: S,
+    cleanup: F,
+) -> Result<Finalized<K>, E>
+where
+    K: Resource + Clone + DeserializeOwned + Debug,
+    S: AsRef<str>,
+    F: FnOnce(K) -> Fut,
+    Fut: Future<Output = Result<bool, E>>,
+    E: From<kube::Error>,
+{
+    let finalizer = finalizer.as_ref();
+    let deleting = resource.meta().deletion_timestamp.is_some();
+
+    match (deleting, resource.meta().has_finalizer(finalizer)) {
+        (false, true) => Ok(Finalized::Active(resource)),
+        (false, false) => {
+            log::debug!("Adding finalizer: {}", finalizer);
+            let mut meta = resource.meta().clone();
+            meta.add_finalizer(finalizer);
+            Ok(Finalized::Active(
+                patch_finalizers(api, &resource, meta.finalizers).await?,
+            ))
+        }
+        (true, false) => Ok(Finalized::Finalized),
+        (true, true) => {
+            if cleanup(resource.clone()).await? {
+                log::debug!("Cleanup complete, removing finalizer: {}", finalizer);
+                let mut meta = resource.meta().clone();
+                meta.remove_finalizer(finalizer);
+                patch_finalizers(api, &resource, meta.finalizers).await?;
+                Ok(Finalized::Finalized)
+            } else {
+                Ok(Finalized::Pending(resource))
+            }
+        }
+    }
+}
+
+/// Set the finalizers of a resource, guarded by its resource version.
+async fn patch_finalizers<K>(
+    api: &Api<K>,
+    resource: &K,
+    finalizers: Option<Vec<String>>,
+) -> Result<K, kube::Error>
+where
+    K: Resource + Clone + DeserializeOwned + Debug,
+{
+    let patch = json!({
+        "metadata": {
+            "resourceVersion": resource.resource_version(),
+            "finalizers": finalizers,
+        }
+    });
+
+    api.patch(
+        &resource.name_any(),
+        &PatchParams::default(),
+        &Patch::Merge(&patch),
+    )
+    .await
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::testing::{config_map, FakeServer};
+    use k8s_openapi::api::core::v1::ConfigMap;
+    use kube::api::DeleteParams;
+    use std::cell::Cell;
+
+    const FINALIZER: &str = "example.com/cleanup";
+
+    #[tokio::test]
+    async fn test_add_finalizer() {
+        let server = FakeServer::new();
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+        let cm = server.insert(config_map("foo").build());
+
+        let outcome = finalize(&api, cm, FINALIZER, |_| async {
+            panic!("Must not run the cleanup")
+        })
+        .await;
+
+        match outcome {
+            Ok::<_, kube::Error>(Finalized::Active(cm)) => {
+                assert_eq!(cm.metadata.finalizers, Some(vec![FINALIZER.to_string()]))
+            }
+            other => panic!("Unexpected outcome: {:?}", other),
+        }
+        assert_eq!(
+            server
+                .get::<ConfigMap>(Some("default"), "foo")
+                .unwrap()
+                .metadata
+                .finalizers,
+            Some(vec![FINALIZER.to_string()])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_cleanup() {
+        let server = FakeServer::new();
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+        server.insert(config_map("foo").finalizer(FINALIZER).build());
+        api.delete("foo", &DeleteParams::default()).await.unwrap();
+
+        let cleanups = Cell::new(0);
+        let cleanup = |complete: bool| {
+            let cleanups = &cleanups;
+            move |cm: ConfigMap| async move {
+                assert!(cm.metadata.deletion_timestamp.is_some());
+                cleanups.set(cleanups.get() + 1);
+                Ok::<_, kube::Error>(complete)
+            }
+        };
+
+        // the cleanup is still in progress, the finalizer is kept
+
+        let cm = api.get("foo").await.unwrap();
+        let outcome = finalize(&api, cm, FINALIZER, cleanup(false)).await.unwrap();
+        assert!(matches!(outcome, Finalized::Pending(_)));
+        assert_eq!(cleanups.get(), 1);
+        assert!(server.get::<ConfigMap>(Some("default"), "foo").is_some());
+
+        // the cleanup is complete, the finalizer gets removed
+
+        let cm = api.get("foo").await.unwrap();
+        let outcome = finalize(&api, cm, FINALIZER, cleanup(true)).await.unwrap();
+        assert!(matches!(outcome, Finalized::Finalized));
+        assert_eq!(cleanups.get(), 2);
+        assert!(server.get::<ConfigMap>(Some("default"), "foo").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_other_finalizer() {
+        let server = FakeServer::new();
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+        server.insert(config_map("foo").finalizer("example.com/other").build());
+        api.delete("foo", &DeleteParams::default()).await.unwrap();
+
+        // the resource is being deleted, but doesn't carry our finalizer
+
+        let cm = api.get("foo").await.unwrap();
+        let outcome = finalize(&api, cm, FINALIZER, |_| async {
+            panic!("Must not run the cleanup")
+        })
+        .await;
+        assert!(matches!(
+            outcome,
+            Ok::<_, kube::Error>(Finalized::Finalized)
+        ));
+        assert_eq!(
+            server
+                .get::<ConfigMap>(Some("default"), "foo")
+                .unwrap()
+                .metadata
+                .finalizers,
+            Some(vec!["example.com/other".to_string()])
+        );
+    }
+}
@@ -0,0 +1,149 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+use crate::{
+    install::{
+        meta::{Meta, OwnedBy},
+        Delete,
+    },
+    selectors::{ToSelector, WithSelectors},
+};
+use kube::{
+    api::{DeleteParams, ListParams, Preconditions},
+    Api, Error, Resource, ResourceExt,
+};
+use serde::de::DeserializeOwned;
+use std::{collections::HashSet, fmt::Debug};
+
+/// Delete resources controlled by an owner, which are no longer desired.
+///
+/// All resources matching the label selector are listed. Those which are controlled by the owner
+/// (see [`OwnedBy::is_owned_by_controller`]), but whose name is not part of the desired set, get
+/// deleted using [`Delete::delete_optionally`]. Resources which are already being deleted are
+/// skipped.
+///
+/// Returns the resources which got deleted. This includes resources whose deletion is still
+/// pending, e.g. because of finalizers.
+///
+/// ```ignore
+/// // the listeners of the spec, which got created with `owned_by_controller(&resource)`
+/// let desired = resource.spec.listeners.iter().map(|l| l.name.as_str());
+/// let removed = delete_orphans(&config_maps, &resource, &labels, desired).await?;
+/// ```
+///
+/// Each delete carries the UID of the listed resource as precondition. So a resource which got
+/// re-created in the meantime is not deleted, and not reported.
+pub async fn delete_orphans<K, O, S, I>(
+    api: &Api<K>,
+    owner: &O,
+    selector: &S,
+    desired: I,
+) -> Result<Vec<K>, anyhow::Error>
+where
+    K: Resource<DynamicType = ()> + Clone + DeserializeOwned + Send + Debug,
+    O: Meta,
+    S: ToSelector + ?Sized,
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    let desired: HashSet<String> = desired
+        .into_iter()
+        .map(|name| name.as_ref().to_string())
+        .collect();
+
+    let lp = ListParams::default().with_labels(selector);
+
+    let mut removed = Vec::new();
+
+    for resource in api.list(&lp).await? {
+        let name = resource.name_any();
+        if desired.contains(&name)
+            || resource.meta().deletion_timestamp.is_some()
+            || !resource.is_owned_by_controller(owner)?
+        {
+            continue;
+        }
+
+        log::debug!("Deleting orphaned resource: {}", name);
+
+        let dp = DeleteParams {
+            preconditions: Some(Preconditions {
+                uid: resource.meta().uid.clone(),
+                resource_version: None,
+            }),
+            ..Default::default()
+        };
+
+        match api.delete_optionally(&name, &dp).await {
+            Ok(_) => removed.push(resource),
+            // re-created in the meantime, it is no longer the resource we listed
+            Err(Error::Api(err)) if err.code == 409 => {
+                log::debug!("Orphaned resource was replaced: {}", name);
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::{
+        selectors::Selector,
+        testing::{self, ConfigMapBuilder, FakeServer},
+    };
+    use k8s_openapi::api::core::v1::ConfigMap;
+
+    fn config_map(name: &str) -> ConfigMapBuilder {
+        testing::config_map(name).label("app", "test")
+    }
+
+    fn names(resources: &[ConfigMap]) -> Vec<String> {
+        resources.iter().map(|r| r.name_any()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_delete_orphans() {
+        let server = FakeServer::new();
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+
+        let owner = server.insert(config_map("owner").build());
+        let other = server.insert(config_map("other").build());
+
+        server.insert(config_map("a").owned_by(&owner).build());
+        server.insert(config_map("b").owned_by(&owner).build());
+        server.insert(config_map("c").owned_by(&owner).build());
+        server.insert(config_map("d").owned_by(&other).build());
+        server.insert(config_map("e").build());
+
+        let selector: Selector = "app=test".parse().unwrap();
+
+        let removed = delete_orphans(&api, &owner, &selector, ["a"])
+            .await
+            .unwrap();
+        assert_eq!(names(&removed), vec!["b", "c"]);
+        assert_eq!(
+            names(&server.list::<ConfigMap>(Some("default"))),
+            vec!["a", "d", "e", "other", "owner"]
+        );
+
+        // nothing left to remove
+        let removed = delete_orphans(&api, &owner, &selector, ["a"])
+            .await
+            .unwrap();
+        assert!(removed.is_empty());
+    }
+}
@@ -11,10 +11,88 @@
  * SPDX-License-Identifier: EPL-2.0
  */
 
-use crate::utils::UseOrCreate;
-use anyhow::{anyhow, Error};
-use k8s_openapi::apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference};
-use std::borrow::Cow;
+use crate::{
+    selectors::{Expression, Selector},
+    utils::UseOrCreate,
+};
+use k8s_openapi::{
+    api::core::v1::ObjectReference,
+    apimachinery::pkg::apis::meta::v1::{ObjectMeta, OwnerReference},
+};
+use std::{
+    borrow::Cow,
+    fmt::{Display, Formatter},
+};
+
+/// The label carrying the UID of the owner of a soft owned object.
+pub const OWNER_UID_LABEL: &str = "operator-framework/owner-uid";
+/// The annotation carrying a reference to the owner of a soft owned object.
+pub const OWNER_ANNOTATION: &str = "operator-framework/owner";
+
+/// An invalid ownership relation.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OwnershipError {
+    /// The owner has no name.
+    MissingName,
+    /// The owner has no UID, e.g. because it was not yet created.
+    MissingUid,
+    /// The owner is located in a different namespace than the owned object.
+    CrossNamespace {
+        namespace: String,
+        owner_namespace: String,
+    },
+    /// The owned object is cluster scoped, but the owner is namespaced.
+    NamespacedOwner { owner_namespace: String },
+    /// The object already has a different controller.
+    AlreadyControlled,
+}
+
+impl Display for OwnershipError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingName => write!(f, "Missing name"),
+            Self::MissingUid => write!(f, "Missing UID"),
+            Self::CrossNamespace {
+                namespace,
+                owner_namespace,
+            } => write!(
+                f,
+                "If both objects are namespaced, they must belong to the same namespace (object: {}, owner: {})",
+                namespace, owner_namespace
+            ),
+            Self::NamespacedOwner { owner_namespace } => write!(
+                f,
+                "Cluster scoped object must not have a namespaced owner (owner: {})",
+                owner_namespace
+            ),
+            Self::AlreadyControlled => write!(f, "Object already has a controller"),
+        }
+    }
+}
+
+impl std::error::Error for OwnershipError {}
+
+/// Check if an object may have an owner reference to the owner.
+///
+/// Kubernetes ignores references to owners in a different namespace, and forbids cluster scoped
+/// objects to have namespaced owners. For those cases, see [`SoftOwnedBy`].
+pub fn validate_owner<O, R>(object: &O, owner: &R) -> Result<(), OwnershipError>
+where
+    O: Meta,
+    R: Meta,
+{
+    match (&object.metadata().namespace, &owner.metadata().namespace) {
+        (None, None) | (Some(_), None) => Ok(()),
+        (Some(namespace), Some(owner_namespace)) if namespace == owner_namespace => Ok(()),
+        (Some(namespace), Some(owner_namespace)) => Err(OwnershipError::CrossNamespace {
+            namespace: namespace.clone(),
+            owner_namespace: owner_namespace.clone(),
+        }),
+        (None, Some(owner_namespace)) => Err(OwnershipError::NamespacedOwner {
+            owner_namespace: owner_namespace.clone(),
+        }),
+    }
+}
 
 pub trait Meta {
     fn metadata(&self) -> &ObjectMeta;
@@ -24,21 +102,122 @@ pub trait Meta {
     fn api_version(&self) -> Cow<'_, str>;
 }
 
+/// Marker for the implementation of [`Finalizers`] on [`ObjectMeta`].
+pub enum OnMeta {}
+
+/// Marker for the implementation of [`Finalizers`] on resources.
+pub enum OnResource {}
+
+/// Manage the finalizers of a resource.
+///
+/// This is implemented for [`ObjectMeta`] as well as for all resources. The type parameter only
+/// distinguishes the two implementations, and can be inferred.
+pub trait Finalizers<M> {
+    /// Check if the finalizer is present.
+    fn has_finalizer<S>(&self, finalizer: S) -> bool
+    where
+        S: AsRef<str>;
+
+    /// Add a finalizer, returning `true` if it was not present before.
+    fn add_finalizer<S>(&mut self, finalizer: S) -> bool
+    where
+        S: AsRef<str>;
+
+    /// Remove a finalizer, returning `true` if it was present before.
+    fn remove_finalizer<S>(&mut self, finalizer: S) -> bool
+    where
+        S: AsRef<str>;
+}
+
+impl Finalizers<OnMeta> for ObjectMeta {
+    fn has_finalizer<S>(&self, finalizer: S) -> bool
+    where
+        S: AsRef<str>,
+    {
+        self.finalizers
+            .as_ref()
+            .map(|finalizers| finalizers.iter().any(|f| f == finalizer.as_ref()))
+            .unwrap_or_default()
+    }
+
+    fn add_finalizer<S>(&mut self, finalizer: S) -> bool
+    where
+        S: AsRef<str>,
+    {
+        if self.has_finalizer(finalizer.as_ref()) {
+            false
+        } else {
+            self.finalizers.use_or_create(|finalizers| {
+                finalizers.push(finalizer.as_ref().to_string());
+            });
+            true
+        }
+    }
+
+    fn remove_finalizer<S>(&mut self, finalizer: S) -> bool
+    where
+        S: AsRef<str>,
+    {
+        if let Some(finalizers) = &mut self.finalizers {
+            let len = finalizers.len();
+            finalizers.retain(|f| f != finalizer.as_ref());
+            let removed = len != finalizers.len();
+            if finalizers.is_empty() {
+                self.finalizers = None;
+            }
+            removed
+        } else {
+            false
+        }
+    }
+}
+
+impl<K> Finalizers<OnResource> for K
+where
+    K: Meta,
+{
+    fn has_finalizer<S>(&self, finalizer: S) -> bool
+    where
+        S: AsRef<str>,
+    {
+        self.metadata().has_finalizer(finalizer)
+    }
+
+    fn add_finalizer<S>(&mut self, finalizer: S) -> bool
+    where
+        S: AsRef<str>,
+    {
+        self.metadata_mut().add_finalizer(finalizer)
+    }
+
+    fn remove_finalizer<S>(&mut self, finalizer: S) -> bool
+    where
+        S: AsRef<str>,
+    {
+        self.metadata_mut().remove_finalizer(finalizer)
+    }
+}
+
 pub trait OwnedBy<R> {
+    /// Add an owner reference, failing if the owner is invalid (see [`validate_owner`]).
     fn owned_by(
         &mut self,
         resource: &R,
         controller: bool,
         block_owner_deletion: Option<bool>,
-    ) -> Result<(), anyhow::Error>;
+    ) -> Result<(), OwnershipError>;
 
-    fn owned_by_controller(&mut self, resource: &R) -> Result<(), anyhow::Error> {
+    fn owned_by_controller(&mut self, resource: &R) -> Result<(), OwnershipError> {
         self.owned_by(resource, true, None)
     }
 
-    fn is_owned_by(&self, owner: &R, controller: Option<bool>) -> Result<bool, anyhow::Error>;
+    /// Check if the object has an owner reference to the owner.
+    ///
+    /// This only checks owner references, not soft ownership (see
+    /// [`SoftOwnedBy::is_soft_owned_by`]).
+    fn is_owned_by(&self, owner: &R, controller: Option<bool>) -> Result<bool, OwnershipError>;
 
-    fn is_owned_by_controller(&self, owner: &R) -> Result<bool, anyhow::Error> {
+    fn is_owned_by_controller(&self, owner: &R) -> Result<bool, OwnershipError> {
         self.is_owned_by(owner, Some(true))
     }
 }
@@ -75,11 +254,22 @@ pub trait AsOwner {
         &self,
         controller: Option<bool>,
         block_owner_deletion: Option<bool>,
-    ) -> Result<OwnerReference, anyhow::Error>;
+    ) -> Result<OwnerReference, OwnershipError>;
 
-    fn as_controller_owner(&self) -> Result<OwnerReference, anyhow::Error> {
+    fn as_controller_owner(&self) -> Result<OwnerReference, OwnershipError> {
         self.as_owner(Some(true), None)
     }
+
+    /// Create an owner reference for an object, failing if the object can't reference this owner
+    /// (see [`validate_owner`]).
+    fn as_owner_of<O>(
+        &self,
+        object: &O,
+        controller: Option<bool>,
+        block_owner_deletion: Option<bool>,
+    ) -> Result<OwnerReference, OwnershipError>
+    where
+        O: Meta;
 }
 
 impl<K> AsOwner for K
@@ -90,18 +280,18 @@ where
         &self,
         controller: Option<bool>,
         block_owner_deletion: Option<bool>,
-    ) -> Result<OwnerReference, Error> {
+    ) -> Result<OwnerReference, OwnershipError> {
         let name = self
             .metadata()
             .name
             .as_ref()
-            .ok_or_else(|| anyhow!("Missing name"))?
+            .ok_or(OwnershipError::MissingName)?
             .clone();
         let uid = self
             .metadata()
             .uid
             .as_ref()
-            .ok_or_else(|| anyhow!("Missing UID"))?
+            .ok_or(OwnershipError::MissingUid)?
             .clone();
 
         Ok(OwnerReference {
@@ -113,6 +303,42 @@ where
             block_owner_deletion,
         })
     }
+
+    fn as_owner_of<O>(
+        &self,
+        object: &O,
+        controller: Option<bool>,
+        block_owner_deletion: Option<bool>,
+    ) -> Result<OwnerReference, OwnershipError>
+    where
+        O: Meta,
+    {
+        validate_owner(object, self)?;
+        self.as_owner(controller, block_owner_deletion)
+    }
+}
+
+/// Create a reference to an object, e.g. for the `involvedObject` of an event.
+pub trait AsObjectReference {
+    fn as_object_reference(&self) -> ObjectReference;
+}
+
+impl<K> AsObjectReference for K
+where
+    K: Meta,
+{
+    fn as_object_reference(&self) -> ObjectReference {
+        let meta = self.metadata();
+        ObjectReference {
+            api_version: Some(self.api_version().to_string()),
+            kind: Some(self.kind().to_string()),
+            name: meta.name.clone(),
+            namespace: meta.namespace.clone(),
+            uid: meta.uid.clone(),
+            resource_version: meta.resource_version.clone(),
+            field_path: None,
+        }
+    }
 }
 
 impl<K> Meta for K
@@ -146,38 +372,20 @@ where
         resource: &R,
         controller: bool,
         block_owner_deletion: Option<bool>,
-    ) -> Result<(), anyhow::Error> {
-        match (&self.metadata().namespace, &resource.metadata().namespace) {
-            (None, None) => Ok(()),
-            (Some(_), None) => Ok(()),
-
-            (Some(obj_ns), Some(owner_ns)) => {
-                if obj_ns == owner_ns {
-                    Ok(())
-                } else {
-                    Err(anyhow!(
-                        "If both objects are namespaced, they must belong to the same namespace"
-                    ))
-                }
-            }
-            (None, Some(_)) => Err(anyhow!(
-                "Cluster scoped object must not have a namespaced owner"
-            )),
-        }?;
-
-        let owner = resource.as_owner(Some(controller), block_owner_deletion)?;
+    ) -> Result<(), OwnershipError> {
+        let owner = resource.as_owner_of(self, Some(controller), block_owner_deletion)?;
 
         let mut found = None;
 
         self.metadata_mut()
             .owner_references
-            .use_or_create_err(|owners| {
+            .use_or_create(|owners| {
                 for (idx, o) in owners.iter().enumerate() {
                     if owner.is_same_owner(&o) {
                         found = Some(idx);
                     } else if controller {
                         match o.controller {
-                            Some(true) => Err(anyhow!("Object already has a controller")),
+                            Some(true) => Err(OwnershipError::AlreadyControlled),
                             _ => Ok(()),
                         }?;
                     }
@@ -200,7 +408,7 @@ where
         Ok(())
     }
 
-    fn is_owned_by(&self, owner: &R, controlled: Option<bool>) -> Result<bool, anyhow::Error> {
+    fn is_owned_by(&self, owner: &R, controlled: Option<bool>) -> Result<bool, OwnershipError> {
         let owner = owner.as_owner(controlled, None)?;
 
         if let Some(owner_refs) = &self.metadata().owner_references {
@@ -215,6 +423,105 @@ where
     }
 }
 
+/// Ownership, recorded using a label and an annotation instead of an owner reference.
+///
+/// This supports relations which can't use owner references, like owners in a different
+/// namespace (see [`validate_owner`]). The label carries the UID of the owner, so that owned
+/// objects can be found using [`soft_owner_selector`]. An object can only have one soft owner,
+/// and soft owned objects are not garbage collected.
+///
+/// Soft ownership is independent of owner references: [`OwnedBy::is_owned_by`] doesn't consider
+/// soft owners, and [`SoftOwnedBy::is_soft_owned_by`] doesn't consider owner references. Code
+/// which supports both needs to check both.
+pub trait SoftOwnedBy<R> {
+    /// Set the soft owner, replacing an existing one.
+    fn soft_owned_by(&mut self, owner: &R) -> Result<(), OwnershipError>;
+
+    /// Check if the owner is the soft owner of the object.
+    ///
+    /// Both the label and the annotation must refer to the owner, so an object with a stale or
+    /// hand-edited annotation doesn't match.
+    fn is_soft_owned_by(&self, owner: &R) -> bool;
+}
+
+impl<K, R> SoftOwnedBy<R> for K
+where
+    K: Meta,
+    R: Meta,
+{
+    fn soft_owned_by(&mut self, owner: &R) -> Result<(), OwnershipError> {
+        owner
+            .metadata()
+            .name
+            .as_ref()
+            .ok_or(OwnershipError::MissingName)?;
+        let uid = owner
+            .metadata()
+            .uid
+            .clone()
+            .ok_or(OwnershipError::MissingUid)?;
+
+        let reference = ObjectReference {
+            resource_version: None,
+            ..owner.as_object_reference()
+        };
+        let reference = serde_json::to_string(&reference).expect("object reference must serialize");
+
+        let meta = self.metadata_mut();
+        meta.labels.use_or_create(|labels| {
+            labels.insert(OWNER_UID_LABEL.to_string(), uid);
+        });
+        meta.annotations.use_or_create(|annotations| {
+            annotations.insert(OWNER_ANNOTATION.to_string(), reference);
+        });
+
+        Ok(())
+    }
+
+    fn is_soft_owned_by(&self, owner: &R) -> bool {
+        let owner = owner.metadata();
+        let uid = match (&owner.uid, &self.metadata().labels) {
+            (Some(uid), Some(labels)) if labels.get(OWNER_UID_LABEL) == Some(uid) => uid,
+            _ => return false,
+        };
+
+        match soft_owner(self) {
+            Some(reference) => {
+                reference.uid.as_ref() == Some(uid)
+                    && reference.name == owner.name
+                    && reference.namespace == owner.namespace
+            }
+            None => false,
+        }
+    }
+}
+
+/// Get the soft owner of an object, see [`SoftOwnedBy`].
+pub fn soft_owner<O>(object: &O) -> Option<ObjectReference>
+where
+    O: Meta,
+{
+    object
+        .metadata()
+        .annotations
+        .as_ref()?
+        .get(OWNER_ANNOTATION)
+        .and_then(|reference| serde_json::from_str(reference).ok())
+}
+
+/// Create a label selector, selecting all objects soft owned by the owner.
+pub fn soft_owner_selector<R>(owner: &R) -> Result<Selector, OwnershipError>
+where
+    R: Meta,
+{
+    let uid = owner
+        .metadata()
+        .uid
+        .clone()
+        .ok_or(OwnershipError::MissingUid)?;
+    Ok(Selector::new().with(Expression::Equal(OWNER_UID_LABEL.to_string(), uid)))
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -384,4 +691,131 @@ mod tests {
         assert_eq!(true, config_map_1.is_owned_by(&config_map_2, None).unwrap());
         assert_eq!(true, config_map_1.is_owned_by(&config_map_3, None).unwrap());
     }
+
+    #[test]
+    fn test_finalizers() {
+        let mut config_map: ConfigMap = new_cm(Some("ns1"), "cm1", "123");
+
+        assert!(!config_map.has_finalizer("foo"));
+        assert!(config_map.add_finalizer("foo"));
+        assert!(!config_map.add_finalizer("foo"));
+        assert!(config_map.add_finalizer("bar"));
+        assert!(config_map.has_finalizer("foo"));
+        assert_eq!(
+            config_map.metadata.finalizers,
+            Some(vec!["foo".to_string(), "bar".to_string()])
+        );
+
+        assert!(config_map.remove_finalizer("foo"));
+        assert!(!config_map.remove_finalizer("foo"));
+        assert!(!config_map.has_finalizer("foo"));
+        assert!(config_map.metadata.remove_finalizer("bar"));
+        assert_eq!(config_map.metadata.finalizers, None);
+    }
+
+    #[test]
+    fn test_as_object_reference() {
+        let cm = new_cm(Some("ns1"), "foo", "1234");
+        assert_eq!(
+            cm.as_object_reference(),
+            ObjectReference {
+                api_version: Some("v1".into()),
+                kind: Some("ConfigMap".into()),
+                name: Some("foo".into()),
+                namespace: Some("ns1".into()),
+                uid: Some("1234".into()),
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_owned_by_invalid() {
+        let mut config_map_1: ConfigMap = new_cm(Some("ns1"), "cm1", "123");
+        let mut config_map_2: ConfigMap = new_cm(None, "cm2", "456");
+        let owner: ConfigMap = new_cm(Some("ns2"), "owner", "789");
+
+        assert_eq!(
+            config_map_1.owned_by_controller(&owner),
+            Err(OwnershipError::CrossNamespace {
+                namespace: "ns1".into(),
+                owner_namespace: "ns2".into(),
+            })
+        );
+        assert_eq!(
+            config_map_2.owned_by_controller(&owner),
+            Err(OwnershipError::NamespacedOwner {
+                owner_namespace: "ns2".into(),
+            })
+        );
+        assert_eq!(
+            owner.as_owner_of(&config_map_1, None, None),
+            Err(OwnershipError::CrossNamespace {
+                namespace: "ns1".into(),
+                owner_namespace: "ns2".into(),
+            })
+        );
+        assert_eq!(
+            new_cm(Some("ns1"), "owner", "789").as_owner_of(&config_map_1, None, None),
+            new_cm(Some("ns1"), "owner", "789").as_owner(None, None)
+        );
+
+        assert!(config_map_1.metadata.owner_references.is_none());
+        assert!(config_map_2.metadata.owner_references.is_none());
+    }
+
+    #[test]
+    fn test_soft_owned_by() {
+        let mut config_map: ConfigMap = new_cm(Some("ns1"), "cm1", "123");
+        let owner: ConfigMap = new_cm(Some("ns2"), "owner", "456");
+        let other: ConfigMap = new_cm(Some("ns2"), "other", "789");
+
+        assert!(!config_map.is_soft_owned_by(&owner));
+        assert_eq!(soft_owner(&config_map), None);
+
+        config_map.soft_owned_by(&owner).unwrap();
+
+        assert!(config_map.is_soft_owned_by(&owner));
+        assert!(!config_map.is_soft_owned_by(&other));
+        assert_eq!(
+            soft_owner(&config_map),
+            Some(ObjectReference {
+                api_version: Some("v1".into()),
+                kind: Some("ConfigMap".into()),
+                name: Some("owner".into()),
+                namespace: Some("ns2".into()),
+                uid: Some("456".into()),
+                ..Default::default()
+            })
+        );
+        assert!(soft_owner_selector(&owner)
+            .unwrap()
+            .matches(config_map.metadata.labels.as_ref().unwrap()));
+        assert!(!soft_owner_selector(&other)
+            .unwrap()
+            .matches(config_map.metadata.labels.as_ref().unwrap()));
+
+        // the annotation must match the label
+        let mut inconsistent = config_map.clone();
+        inconsistent.metadata.annotations.as_mut().unwrap().insert(
+            OWNER_ANNOTATION.to_string(),
+            r#"{"name":"other","namespace":"ns2","uid":"456"}"#.to_string(),
+        );
+        assert!(!inconsistent.is_soft_owned_by(&owner));
+        assert!(!inconsistent.is_soft_owned_by(&other));
+        inconsistent.metadata.annotations = None;
+        assert!(!inconsistent.is_soft_owned_by(&owner));
+
+        // replace the owner
+        config_map.soft_owned_by(&other).unwrap();
+        assert!(!config_map.is_soft_owned_by(&owner));
+        assert!(config_map.is_soft_owned_by(&other));
+
+        let mut missing_uid = new_cm(Some("ns2"), "new", "");
+        missing_uid.metadata.uid = None;
+        assert_eq!(
+            config_map.soft_owned_by(&missing_uid),
+            Err(OwnershipError::MissingUid)
+        );
+    }
 }
@@ -13,10 +13,14 @@
 pub mod config;
 pub mod container;
 mod delete;
+mod finalizer;
+mod gc;
 pub mod meta;
 mod resources;
 mod value;
 
 pub use self::delete::*;
+pub use self::finalizer::*;
+pub use self::gc::*;
 pub use self::resources::*;
 pub use self::value::*;
@@ -1,4 +1,5 @@
 use crate::install::container::ApplyEnvironmentVariable;
+use crate::tracker::{ConfigTracker, Encoding};
 use anyhow::Result;
 use async_trait::async_trait;
 use core::fmt::{self, Formatter};
@@ -237,6 +238,78 @@ impl ValueOrReference {
             Self::Secret(selector) => reader.read_secret(selector).await,
         }
     }
+
+    /// The identity of the source of the value.
+    pub fn identity(&self) -> String {
+        match self {
+            Self::Value(_) => "value".to_string(),
+            Self::ConfigMap(selector) => format!(
+                "configMap/{}/{}",
+                selector.name.as_deref().unwrap_or_default(),
+                selector.key
+            ),
+            Self::Secret(selector) => format!(
+                "secret/{}/{}",
+                selector.name.as_deref().unwrap_or_default(),
+                selector.key
+            ),
+        }
+    }
+
+    /// Read the actual value, and track it.
+    ///
+    /// The tracker is fed with the identity of the source, as well as with the resolved value. So
+    /// that a change of the referenced value, as well as a change of the reference, will result in
+    /// a changed tracker state.
+    ///
+    /// The legacy encoding doesn't separate the tracked data, so the identity would be ambiguous
+    /// (e.g. `("ab", "c")` and `("a", "bc")`). It only tracks the value, if present, keeping the
+    /// state compatible with tracking the values directly.
+    pub async fn read_and_track<R>(
+        &self,
+        reader: &R,
+        tracker: &mut ConfigTracker,
+    ) -> Result<Option<String>>
+    where
+        R: Reader,
+    {
+        let value = self.read_value(reader).await?;
+
+        match (tracker.encoding(), &value) {
+            (Encoding::Legacy, Some(value)) => tracker.track(value),
+            (Encoding::Legacy, None) => {}
+            (_, Some(value)) => {
+                tracker.track(self.identity());
+                tracker.track([1u8]);
+                tracker.track(value);
+            }
+            (_, None) => {
+                tracker.track(self.identity());
+                tracker.track([0u8]);
+            }
+        }
+
+        Ok(value)
+    }
+}
+
+/// Read a set of values, and track them.
+///
+/// Returns the resolved values, in the same order. See [`ValueOrReference::read_and_track`].
+pub async fn read_and_track_values<'a, R, I>(
+    reader: &R,
+    values: I,
+    tracker: &mut ConfigTracker,
+) -> Result<Vec<Option<String>>>
+where
+    R: Reader,
+    I: IntoIterator<Item = &'a ValueOrReference>,
+{
+    let mut result = Vec::new();
+    for value in values {
+        result.push(value.read_and_track(reader, tracker).await?);
+    }
+    Ok(result)
 }
 
 impl<'de> Deserialize<'de> for ValueOrReference {
@@ -390,4 +463,80 @@ mod test {
 
         Ok(())
     }
+
+    struct MockReader(Option<String>);
+
+    #[async_trait]
+    impl Reader for MockReader {
+        async fn read_configmap(&self, _: &ConfigMapKeySelector) -> Result<Option<String>> {
+            Ok(self.0.clone())
+        }
+
+        async fn read_secret(&self, _: &SecretKeySelector) -> Result<Option<String>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    async fn track(reader: &MockReader, values: &[ValueOrReference]) -> Result<String> {
+        track_with(ConfigTracker::v2(), reader, values).await
+    }
+
+    async fn track_with(
+        mut tracker: ConfigTracker,
+        reader: &MockReader,
+        values: &[ValueOrReference],
+    ) -> Result<String> {
+        read_and_track_values(reader, values, &mut tracker).await?;
+        Ok(tracker.current_hash())
+    }
+
+    fn secret(name: &str, key: &str) -> ValueOrReference {
+        ValueOrReference::Secret(SecretKeySelector {
+            name: Some(name.to_string()),
+            key: key.to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_track() -> Result<()> {
+        let foo = MockReader(Some("foo".into()));
+        let bar = MockReader(Some("bar".into()));
+        let none = MockReader(None);
+
+        let values = [secret("s1", "k1")];
+
+        // change of the referenced value
+        assert_ne!(track(&foo, &values).await?, track(&bar, &values).await?);
+        assert_ne!(track(&foo, &values).await?, track(&none, &values).await?);
+        assert_eq!(track(&foo, &values).await?, track(&foo, &values).await?);
+
+        // change of the reference
+        assert_ne!(
+            track(&foo, &values).await?,
+            track(&foo, &[secret("s1", "k2")]).await?
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_track_legacy() -> Result<()> {
+        let foo = MockReader(Some("foo".into()));
+        let values = [secret("s1", "k1")];
+
+        // only the value is tracked
+        let mut tracker = ConfigTracker::legacy();
+        tracker.track("foo");
+        assert_eq!(
+            track_with(ConfigTracker::legacy(), &foo, &values).await?,
+            tracker.current_hash()
+        );
+        assert_eq!(
+            track_with(ConfigTracker::legacy(), &foo, &values).await?,
+            track_with(ConfigTracker::legacy(), &foo, &[secret("s1", "k2")]).await?
+        );
+
+        Ok(())
+    }
 }
@@ -0,0 +1,679 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+//! Leader election, using a `coordination.k8s.io/v1` [`Lease`].
+//!
+//! All candidates use the same lease, identifying themselves with a unique identity (e.g. the
+//! name of the pod). The candidate holding the lease is the leader, and must renew the lease
+//! before it expires. Other candidates take over the lease once it expired.
+//!
+//! The expiry of a lease is checked using the local time at which a change of the lease was
+//! observed, not the timestamps stored in the lease. So the clocks of the candidates don't need to
+//! be in sync.
+//!
+//! ```ignore
+//! let elector = LeaderElector::new(client, "my-namespace", "my-operator", pod_name);
+//! let leadership = elector.leadership();
+//!
+//! tokio::spawn(elector.clone().run().for_each(|event| async move {
+//!     log::info!("Leadership: {:?}", event);
+//! }));
+//!
+//! // in the reconciler
+//! if !leadership.is_leader() {
+//!     return Ok(Reconciled::requeue_after(Duration::from_secs(10)));
+//! }
+//! ```
+
+use chrono::{DateTime, Utc};
+use futures::Stream;
+use k8s_openapi::{
+    api::coordination::v1::{Lease, LeaseSpec},
+    apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta},
+};
+use kube::{api::PostParams, Api, Client};
+use std::{
+    fmt::{Display, Formatter},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// The configuration of the leader election.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LeaderElectionConfig {
+    /// The duration candidates wait, after the last observed change of the lease, before trying
+    /// to take over the lease.
+    pub lease_duration: Duration,
+    /// The duration the leader keeps re-trying to renew the lease, before giving up leadership.
+    /// Must be less than the lease duration.
+    pub renew_deadline: Duration,
+    /// The duration between attempts to acquire or renew the lease.
+    pub retry_period: Duration,
+}
+
+impl Default for LeaderElectionConfig {
+    fn default() -> Self {
+        Self {
+            lease_duration: Duration::from_secs(15),
+            renew_deadline: Duration::from_secs(10),
+            retry_period: Duration::from_secs(2),
+        }
+    }
+}
+
+impl LeaderElectionConfig {
+    /// Check that the durations are consistent.
+    ///
+    /// The renew deadline must be less than the lease duration, so that the leader gives up
+    /// leadership before other candidates may take over the lease. The retry period must be less
+    /// than the renew deadline, so that the leader tries to renew at least once.
+    pub fn validate(&self) -> Result<(), InvalidLeaderElectionConfig> {
+        if self.renew_deadline >= self.lease_duration {
+            return Err(InvalidLeaderElectionConfig(
+                "the renew deadline must be less than the lease duration".into(),
+            ));
+        }
+        if self.retry_period.is_zero() || self.retry_period >= self.renew_deadline {
+            return Err(InvalidLeaderElectionConfig(
+                "the retry period must be greater than zero and less than the renew deadline"
+                    .into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// An inconsistent leader election configuration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidLeaderElectionConfig(pub String);
+
+impl Display for InvalidLeaderElectionConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid leader election configuration: {}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidLeaderElectionConfig {}
+
+/// The result of trying to acquire, or renew, the lease.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Acquisition {
+    /// This candidate holds the lease.
+    Acquired,
+    /// The lease is held by another candidate.
+    Held,
+    /// The lease was modified at the same time, e.g. by another candidate.
+    Conflict,
+}
+
+/// A change of leadership.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LeaderEvent {
+    /// This candidate became the leader.
+    Acquired,
+    /// This candidate lost the leadership.
+    Lost,
+}
+
+/// Check if a candidate currently is the leader.
+#[derive(Clone, Debug, Default)]
+pub struct Leadership(Arc<AtomicBool>);
+
+impl Leadership {
+    pub fn is_leader(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn set(&self, leader: bool) {
+        self.0.store(leader, Ordering::SeqCst);
+    }
+}
+
+/// The last observed state of the lease.
+#[derive(Debug, Default)]
+struct Observed {
+    spec: Option<LeaseSpec>,
+    time: Option<Instant>,
+}
+
+impl Observed {
+    /// Record the current state of the lease, returning `true` if the lease expired.
+    fn observe(&mut self, spec: &LeaseSpec, duration: Duration, now: Instant) -> bool {
+        if self.spec.as_ref() != Some(spec) || self.time.is_none() {
+            self.spec = Some(spec.clone());
+            self.time = Some(now);
+        }
+        match self.time {
+            Some(time) => now.saturating_duration_since(time) >= duration,
+            None => true,
+        }
+    }
+}
+
+/// Runs the leader election for one candidate.
+///
+/// The elector can be cloned, clones share the same state.
+#[derive(Clone)]
+pub struct LeaderElector {
+    api: Api<Lease>,
+    name: String,
+    identity: String,
+    config: LeaderElectionConfig,
+    observed: Arc<Mutex<Observed>>,
+    leadership: Leadership,
+    /// Set once the lease was released, serializes releasing with acquiring and renewing.
+    released: Arc<futures::lock::Mutex<bool>>,
+}
+
+impl LeaderElector {
+    /// Create a new elector, for the lease `name` in `namespace`.
+    ///
+    /// The identity must be unique among all candidates.
+    pub fn new<N, S, I>(client: Client, namespace: N, name: S, identity: I) -> Self
+    where
+        N: AsRef<str>,
+        S: Into<String>,
+        I: Into<String>,
+    {
+        Self {
+            api: Api::namespaced(client, namespace.as_ref()),
+            name: name.into(),
+            identity: identity.into(),
+            config: Default::default(),
+            observed: Default::default(),
+            leadership: Default::default(),
+            released: Default::default(),
+        }
+    }
+
+    /// Set the configuration, failing if it is inconsistent.
+    pub fn with_config(
+        mut self,
+        config: LeaderElectionConfig,
+    ) -> Result<Self, InvalidLeaderElectionConfig> {
+        config.validate()?;
+        self.config = config;
+        Ok(self)
+    }
+
+    /// A handle to check for leadership.
+    pub fn leadership(&self) -> Leadership {
+        self.leadership.clone()
+    }
+
+    /// Try to acquire the lease, or renew it if this candidate already holds it.
+    ///
+    /// Returns [`Acquisition::Acquired`] if this candidate holds the lease afterwards. A conflict,
+    /// caused by another candidate modifying the lease at the same time, is reported as
+    /// [`Acquisition::Conflict`].
+    pub async fn try_acquire_or_renew(&self) -> Result<Acquisition, kube::Error> {
+        let now = Utc::now();
+
+        let lease = match self.api.get(&self.name).await {
+            Ok(lease) => lease,
+            Err(kube::Error::Api(err)) if err.code == 404 => {
+                let lease = Lease {
+                    metadata: ObjectMeta {
+                        name: Some(self.name.clone()),
+                        ..Default::default()
+                    },
+                    spec: Some(acquire(None, &self.identity, &self.config, now)),
+                };
+                return match self.api.create(&PostParams::default(), &lease).await {
+                    Ok(lease) => {
+                        self.observe(&lease);
+                        Ok(Acquisition::Acquired)
+                    }
+                    Err(kube::Error::Api(err)) if err.code == 409 => Ok(Acquisition::Conflict),
+                    Err(err) => Err(err),
+                };
+            }
+            Err(err) => return Err(err),
+        };
+
+        let spec = lease.spec.clone().unwrap_or_default();
+        let expired = self.observe(&lease);
+        let holder = spec.holder_identity.as_deref().unwrap_or_default();
+
+        if !holder.is_empty() && holder != self.identity && !expired {
+            return Ok(Acquisition::Held);
+        }
+
+        let mut new_lease = lease;
+        new_lease.spec = Some(acquire(Some(&spec), &self.identity, &self.config, now));
+
+        match self
+            .api
+            .replace(&self.name, &PostParams::default(), &new_lease)
+            .await
+        {
+            Ok(lease) => {
+                self.observe(&lease);
+                Ok(Acquisition::Acquired)
+            }
+            Err(kube::Error::Api(err)) if err.code == 409 => Ok(Acquisition::Conflict),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Release the lease, if this candidate holds it.
+    ///
+    /// This allows other candidates to take over immediately, instead of waiting for the lease to
+    /// expire. A running election (see [`Self::run`]) of this candidate, or one of its clones,
+    /// reports the loss of the leadership, and ends.
+    pub async fn release(&self) -> Result<(), kube::Error> {
+        // wait for a running attempt to renew the lease
+        let mut released = self.released.lock().await;
+        *released = true;
+        self.leadership.set(false);
+
+        let mut lease = match self.api.get(&self.name).await {
+            Ok(lease) => lease,
+            Err(kube::Error::Api(err)) if err.code == 404 => return Ok(()),
+            Err(err) => return Err(err),
+        };
+
+        let spec = lease.spec.get_or_insert_with(Default::default);
+        if spec.holder_identity.as_deref() != Some(self.identity.as_str()) {
+            return Ok(());
+        }
+
+        let now = MicroTime(Utc::now());
+        spec.holder_identity = None;
+        spec.lease_duration_seconds = Some(1);
+        spec.acquire_time = Some(now.clone());
+        spec.renew_time = Some(now);
+
+        self.api
+            .replace(&self.name, &PostParams::default(), &lease)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Run the leader election, returning a stream of leadership changes.
+    ///
+    /// A candidate which lost the leadership tries to acquire it again, the stream only ends once
+    /// the lease was released (see [`Self::release`]).
+    /// The leadership is lost if the lease was taken over by another candidate, or if the lease
+    /// could not be renewed within the renew deadline. Each attempt is cancelled once the renew
+    /// deadline passed, so a hanging request can't keep the leadership alive.
+    pub fn run(self) -> impl Stream<Item = LeaderEvent> {
+        struct State {
+            elector: LeaderElector,
+            leader: bool,
+            last_renew: Option<Instant>,
+            first: bool,
+        }
+
+        let state = State {
+            elector: self,
+            leader: false,
+            last_renew: None,
+            first: true,
+        };
+
+        futures::stream::unfold(state, |mut state| async move {
+            loop {
+                if !state.first {
+                    tokio::time::sleep(state.elector.config.retry_period).await;
+                }
+                state.first = false;
+
+                let released = state.elector.released.clone();
+                let released = released.lock().await;
+                if *released {
+                    if state.leader {
+                        log::info!("Released lease: {}", state.elector.name);
+                        state.leader = false;
+                        return Some((LeaderEvent::Lost, state));
+                    }
+                    return None;
+                }
+
+                let now = Instant::now();
+                let deadline = state.elector.config.renew_deadline;
+                let timeout = match (state.leader, state.last_renew) {
+                    (true, Some(last)) => {
+                        deadline.saturating_sub(now.saturating_duration_since(last))
+                    }
+                    _ => deadline,
+                };
+                let result =
+                    tokio::time::timeout(timeout, state.elector.try_acquire_or_renew()).await;
+                drop(released);
+
+                // failures are transient, unless the renew deadline passed
+                let expired = |last_renew: Option<Instant>| match last_renew {
+                    Some(last) => Instant::now().saturating_duration_since(last) >= deadline,
+                    None => true,
+                };
+
+                let lost = match result {
+                    Err(_) => {
+                        log::info!("Timeout acquiring or renewing lease");
+                        true
+                    }
+                    Ok(Ok(Acquisition::Acquired)) => {
+                        state.last_renew = Some(now);
+                        if !state.leader {
+                            log::info!("Acquired lease: {}", state.elector.name);
+                            state.leader = true;
+                            state.elector.leadership.set(true);
+                            return Some((LeaderEvent::Acquired, state));
+                        }
+                        false
+                    }
+                    Ok(Ok(Acquisition::Held)) => true,
+                    Ok(Ok(Acquisition::Conflict)) => {
+                        log::debug!("Conflict acquiring or renewing lease");
+                        expired(state.last_renew)
+                    }
+                    Ok(Err(err)) => {
+                        log::info!("Failed to acquire or renew lease: {}", err);
+                        expired(state.last_renew)
+                    }
+                };
+
+                if state.leader && lost {
+                    log::info!("Lost lease: {}", state.elector.name);
+                    state.leader = false;
+                    state.elector.leadership.set(false);
+                    return Some((LeaderEvent::Lost, state));
+                }
+            }
+        })
+    }
+
+    /// Observe the state of the lease, returning `true` if it expired.
+    fn observe(&self, lease: &Lease) -> bool {
+        self.observed.lock().unwrap().observe(
+            &lease.spec.clone().unwrap_or_default(),
+            lease_duration(lease.spec.as_ref(), &self.config),
+            Instant::now(),
+        )
+    }
+}
+
+/// The lease duration, as stored in the lease, or from the configuration.
+fn lease_duration(spec: Option<&LeaseSpec>, config: &LeaderElectionConfig) -> Duration {
+    spec.and_then(|spec| spec.lease_duration_seconds)
+        .map(|seconds| Duration::from_secs(seconds.max(0) as u64))
+        .unwrap_or(config.lease_duration)
+}
+
+/// Create the spec for acquiring, or renewing, the lease.
+fn acquire(
+    current: Option<&LeaseSpec>,
+    identity: &str,
+    config: &LeaderElectionConfig,
+    now: DateTime<Utc>,
+) -> LeaseSpec {
+    let now = MicroTime(now);
+    let lease_duration_seconds = Some(config.lease_duration.as_secs().min(i32::MAX as u64) as i32);
+
+    match current {
+        // renew
+        Some(current) if current.holder_identity.as_deref() == Some(identity) => LeaseSpec {
+            lease_duration_seconds,
+            renew_time: Some(now),
+            ..current.clone()
+        },
+        // take over
+        Some(current) => LeaseSpec {
+            holder_identity: Some(identity.to_string()),
+            lease_duration_seconds,
+            acquire_time: Some(now.clone()),
+            renew_time: Some(now),
+            lease_transitions: Some(current.lease_transitions.unwrap_or_default() + 1),
+        },
+        // create
+        None => LeaseSpec {
+            holder_identity: Some(identity.to_string()),
+            lease_duration_seconds,
+            acquire_time: Some(now.clone()),
+            renew_time: Some(now),
+            lease_transitions: Some(0),
+        },
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_acquire() {
+        let config = LeaderElectionConfig::default();
+        let now = Utc::now();
+
+        let created = acquire(None, "a", &config, now);
+        assert_eq!(
+            created,
+            LeaseSpec {
+                holder_identity: Some("a".into()),
+                lease_duration_seconds: Some(15),
+                acquire_time: Some(MicroTime(now)),
+                renew_time: Some(MicroTime(now)),
+                lease_transitions: Some(0),
+            }
+        );
+
+        let later = now + chrono::Duration::seconds(5);
+        let renewed = acquire(Some(&created), "a", &config, later);
+        assert_eq!(renewed.acquire_time, Some(MicroTime(now)));
+        assert_eq!(renewed.renew_time, Some(MicroTime(later)));
+        assert_eq!(renewed.lease_transitions, Some(0));
+
+        let taken = acquire(Some(&renewed), "b", &config, later);
+        assert_eq!(taken.holder_identity.as_deref(), Some("b"));
+        assert_eq!(taken.acquire_time, Some(MicroTime(later)));
+        assert_eq!(taken.lease_transitions, Some(1));
+    }
+
+    #[test]
+    fn test_observe() {
+        let mut observed = Observed::default();
+        let duration = Duration::from_secs(15);
+        let now = Instant::now();
+
+        let spec = LeaseSpec {
+            holder_identity: Some("a".into()),
+            ..Default::default()
+        };
+
+        assert!(!observed.observe(&spec, duration, now));
+        assert!(!observed.observe(&spec, duration, now + Duration::from_secs(10)));
+        assert!(observed.observe(&spec, duration, now + Duration::from_secs(15)));
+
+        // a change resets the expiry
+        let renewed = LeaseSpec {
+            renew_time: Some(MicroTime(Utc::now())),
+            ..spec
+        };
+        assert!(!observed.observe(&renewed, duration, now + Duration::from_secs(20)));
+        assert!(observed.observe(&renewed, duration, now + Duration::from_secs(35)));
+    }
+
+    #[test]
+    fn test_lease_duration() {
+        let config = LeaderElectionConfig::default();
+        assert_eq!(lease_duration(None, &config), Duration::from_secs(15));
+        assert_eq!(
+            lease_duration(
+                Some(&LeaseSpec {
+                    lease_duration_seconds: Some(1),
+                    ..Default::default()
+                }),
+                &config
+            ),
+            Duration::from_secs(1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_validate() {
+        assert!(LeaderElectionConfig::default().validate().is_ok());
+
+        let config = LeaderElectionConfig {
+            renew_deadline: Duration::from_secs(15),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let client = crate::testing::FakeServer::new().client();
+        assert!(LeaderElector::new(client, "default", "test", "a")
+            .with_config(config)
+            .is_err());
+
+        let config = LeaderElectionConfig {
+            retry_period: Duration::from_secs(10),
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+        let config = LeaderElectionConfig {
+            retry_period: Duration::ZERO,
+            ..Default::default()
+        };
+        assert!(config.validate().is_err());
+    }
+
+    /// A server, which stops responding, or rejects updates with a conflict, when requested.
+    #[derive(Clone, Default)]
+    struct FaultyServer {
+        server: crate::testing::FakeServer,
+        hang: Arc<AtomicBool>,
+        conflict: Arc<AtomicBool>,
+    }
+
+    impl tower::Service<http::Request<hyper::Body>> for FaultyServer {
+        type Response = http::Response<hyper::Body>;
+        type Error = std::convert::Infallible;
+        type Future = futures::future::BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(
+            &mut self,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<(), Self::Error>> {
+            self.server.poll_ready(cx)
+        }
+
+        fn call(&mut self, request: http::Request<hyper::Body>) -> Self::Future {
+            if self.hang.load(Ordering::SeqCst) {
+                Box::pin(futures::future::pending())
+            } else if self.conflict.load(Ordering::SeqCst) && request.method() == http::Method::PUT
+            {
+                let status = serde_json::json!({
+                    "kind": "Status",
+                    "apiVersion": "v1",
+                    "status": "Failure",
+                    "message": "the object has been modified",
+                    "reason": "Conflict",
+                    "code": 409,
+                });
+                let response = http::Response::builder()
+                    .status(http::StatusCode::CONFLICT)
+                    .header(http::header::CONTENT_TYPE, "application/json")
+                    .body(hyper::Body::from(status.to_string()))
+                    .unwrap();
+                Box::pin(futures::future::ready(Ok(response)))
+            } else {
+                Box::pin(self.server.call(request))
+            }
+        }
+    }
+
+    /// Create an elector, with short durations, and wait until it acquired the lease.
+    async fn acquired(
+        server: &FaultyServer,
+    ) -> (
+        LeaderElector,
+        futures::stream::BoxStream<'static, LeaderEvent>,
+    ) {
+        use futures::StreamExt;
+
+        let client = Client::new(server.clone(), "default");
+        let elector = LeaderElector::new(client, "default", "test", "a")
+            .with_config(LeaderElectionConfig {
+                lease_duration: Duration::from_millis(500),
+                renew_deadline: Duration::from_millis(300),
+                retry_period: Duration::from_millis(50),
+            })
+            .unwrap();
+
+        let mut events = elector.clone().run().boxed();
+        assert_eq!(next(&mut events).await, Some(Some(LeaderEvent::Acquired)));
+        assert!(elector.leadership().is_leader());
+
+        (elector, events)
+    }
+
+    /// Wait for the next event, returning `None` if there is none within a second.
+    async fn next(
+        events: &mut futures::stream::BoxStream<'static, LeaderEvent>,
+    ) -> Option<Option<LeaderEvent>> {
+        use futures::StreamExt;
+        tokio::time::timeout(Duration::from_secs(1), events.next())
+            .await
+            .ok()
+    }
+
+    #[tokio::test]
+    async fn test_renew_timeout() {
+        let server = FaultyServer::default();
+        let (elector, mut events) = acquired(&server).await;
+
+        // requests never complete, the leadership must be dropped anyway
+        server.hang.store(true, Ordering::SeqCst);
+        assert_eq!(next(&mut events).await, Some(Some(LeaderEvent::Lost)));
+        assert!(!elector.leadership().is_leader());
+    }
+
+    #[tokio::test]
+    async fn test_renew_conflict() {
+        let server = FaultyServer::default();
+        let (elector, mut events) = acquired(&server).await;
+
+        // a conflict is retried until the renew deadline
+        server.conflict.store(true, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(elector.leadership().is_leader());
+        server.conflict.store(false, Ordering::SeqCst);
+        assert_eq!(next(&mut events).await, None);
+        assert!(elector.leadership().is_leader());
+
+        // but not any longer
+        server.conflict.store(true, Ordering::SeqCst);
+        assert_eq!(next(&mut events).await, Some(Some(LeaderEvent::Lost)));
+        assert!(!elector.leadership().is_leader());
+    }
+
+    #[tokio::test]
+    async fn test_release() {
+        let server = FaultyServer::default();
+        let (elector, mut events) = acquired(&server).await;
+
+        elector.release().await.unwrap();
+        assert!(!elector.leadership().is_leader());
+
+        assert_eq!(next(&mut events).await, Some(Some(LeaderEvent::Lost)));
+        assert_eq!(next(&mut events).await, Some(None));
+        assert!(!elector.leadership().is_leader());
+
+        let lease = server.server.get::<Lease>(Some("default"), "test").unwrap();
+        assert_eq!(lease.spec.unwrap().holder_identity, None);
+    }
+}
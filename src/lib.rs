@@ -11,8 +11,21 @@
  * SPDX-License-Identifier: EPL-2.0
  */
 pub mod conditions;
+pub mod controller;
 pub mod install;
+pub mod leader;
 pub mod process;
+pub mod recorder;
 pub mod selectors;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 pub mod tracker;
 pub mod utils;
+
+// allow the derive macros to refer to this crate from within itself
+extern crate self as operator_framework;
+
+#[doc(hidden)]
+pub mod __private {
+    pub use chrono;
+}
@@ -0,0 +1,696 @@
+/*
+ * Copyright (c) 2020 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+mod retry;
+mod status;
+
+pub use retry::*;
+pub use status::*;
+
+use anyhow::Result;
+
+use kube::{
+    api::{ObjectMeta, Patch, PatchParams, PostParams},
+    Api, Error, Resource,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Debug;
+
+/// A structured diff between two versions of a resource, in the form of a JSON patch.
+pub use json_patch::Patch as Diff;
+
+pub enum Outcome<T> {
+    Created(T),
+    /// The resource was updated, optionally carrying the diff between the old and new version.
+    Updated(T, Option<Diff>),
+    Unchanged(T),
+}
+
+impl<T> Outcome<T> {
+    pub fn resource(self) -> T {
+        match self {
+            Self::Created(r) => r,
+            Self::Updated(r, _) => r,
+            Self::Unchanged(r) => r,
+        }
+    }
+
+    /// The diff of an update, if it was requested.
+    pub fn diff(&self) -> Option<&Diff> {
+        match self {
+            Self::Updated(_, diff) => diff.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// The dry-run mode of an operation.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DryRun {
+    /// Persist all changes.
+    #[default]
+    Disabled,
+    /// Send the request to the server, but let the server discard the changes.
+    Server,
+    /// Don't send any modifying request to the server at all.
+    Client,
+}
+
+impl DryRun {
+    fn post_params(&self) -> PostParams {
+        PostParams {
+            dry_run: matches!(self, Self::Server),
+            ..Default::default()
+        }
+    }
+}
+
+/// Parameters for [`create_or_update_by_with`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CreateOrUpdateParams {
+    /// The policy for re-trying the operation in case of a conflict.
+    pub retry: RetryPolicy,
+    /// Only preview the changes, don't persist them.
+    pub dry_run: DryRun,
+    /// Report the diff between the old and new version in [`Outcome::Updated`].
+    pub diff: bool,
+}
+
+/// The failure of a single create or update attempt.
+enum Failure<E> {
+    Api(Error),
+    Other(E),
+}
+
+/// Create or update a Kubernetes resource.
+///
+/// This uses the default [`CreateOrUpdateParams`], see [`create_or_update_by_with`] for more
+/// details.
+pub async fn create_or_update_by<T, S1, S2, C, F, E, Eq>(
+    api: &Api<T>,
+    namespace: Option<S1>,
+    name: S2,
+    creator: C,
+    eq: Eq,
+    mutator: F,
+) -> Result<Outcome<T>, E>
+where
+    T: Resource + Clone + Debug + DeserializeOwned + Serialize,
+    S1: ToString,
+    S2: AsRef<str>,
+    C: FnMut(ObjectMeta) -> T,
+    F: FnMut(T) -> Result<T, E>,
+    Eq: FnMut(&T, &T) -> bool,
+    E: From<Error>,
+{
+    create_or_update_by_with(
+        api,
+        namespace,
+        name,
+        &Default::default(),
+        creator,
+        eq,
+        mutator,
+    )
+    .await
+}
+
+/// Create or update a Kubernetes resource.
+///
+/// If the resource was modified in the meantime (`Conflict`), or was created by someone else in
+/// the meantime (`AlreadyExists`), the operation is re-tried according to the retry policy of
+/// the provided parameters. Each attempt re-fetches the resource and runs the mutator again.
+pub async fn create_or_update_by_with<T, S1, S2, C, F, E, Eq>(
+    api: &Api<T>,
+    namespace: Option<S1>,
+    name: S2,
+    params: &CreateOrUpdateParams,
+    mut creator: C,
+    mut eq: Eq,
+    mut mutator: F,
+) -> Result<Outcome<T>, E>
+where
+    T: Resource + Clone + Debug + DeserializeOwned + Serialize,
+    S1: ToString,
+    S2: AsRef<str>,
+    C: FnMut(ObjectMeta) -> T,
+    F: FnMut(T) -> Result<T, E>,
+    Eq: FnMut(&T, &T) -> bool,
+    E: From<Error>,
+{
+    let namespace = namespace.map(|s| s.to_string());
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+
+        match create_or_update_once(
+            api,
+            params,
+            namespace.as_deref(),
+            name.as_ref(),
+            &mut creator,
+            &mut eq,
+            &mut mutator,
+        )
+        .await
+        {
+            Ok(outcome) => return Ok(outcome),
+            Err(Failure::Api(err)) if is_conflict(&err) && params.retry.should_retry(attempt) => {
+                log::debug!("CreateOrUpdate - Conflict: {}", err);
+                params.retry.wait(attempt).await;
+            }
+            Err(Failure::Api(err)) => return Err(err.into()),
+            Err(Failure::Other(err)) => return Err(err),
+        }
+    }
+}
+
+/// A single attempt of [`create_or_update_by_with`].
+async fn create_or_update_once<T, C, F, E, Eq>(
+    api: &Api<T>,
+    params: &CreateOrUpdateParams,
+    namespace: Option<&str>,
+    name: &str,
+    creator: &mut C,
+    eq: &mut Eq,
+    mutator: &mut F,
+) -> Result<Outcome<T>, Failure<E>>
+where
+    T: Resource + Clone + Debug + DeserializeOwned + Serialize,
+    C: FnMut(ObjectMeta) -> T,
+    F: FnMut(T) -> Result<T, E>,
+    Eq: FnMut(&T, &T) -> bool,
+{
+    match api.get(name).await {
+        Err(Error::Api(ae)) if ae.code == 404 => {
+            log::debug!("CreateOrUpdate - Err(Api(404))");
+            let object: T = creator(ObjectMeta {
+                namespace: namespace.map(|s| s.to_string()),
+                name: Some(name.to_string()),
+                ..Default::default()
+            });
+            let object = mutator(object).map_err(Failure::Other)?;
+            if params.dry_run != DryRun::Client {
+                api.create(&params.dry_run.post_params(), &object)
+                    .await
+                    .map_err(Failure::Api)?;
+            }
+            Ok(Outcome::Created(object))
+        }
+        Err(e) => {
+            log::info!("Error - {}", e);
+            Err(Failure::Api(e))
+        }
+        Ok(object) => {
+            log::debug!("CreateOrUpdate - Ok(...)");
+            let new_object = mutator(object.clone()).map_err(Failure::Other)?;
+
+            // only update when necessary
+            if !eq(&object, &new_object) {
+                log::debug!("CreateOrUpdate - Changed -> replacing");
+                let diff = match params.diff {
+                    true => Some(diff(&object, &new_object).map_err(Failure::Api)?),
+                    false => None,
+                };
+                if params.dry_run != DryRun::Client {
+                    api.replace(name, &params.dry_run.post_params(), &new_object)
+                        .await
+                        .map_err(Failure::Api)?;
+                }
+                Ok(Outcome::Updated(new_object, diff))
+            } else {
+                Ok(Outcome::Unchanged(new_object))
+            }
+        }
+    }
+}
+
+/// Create or update a Kubernetes resource.
+pub async fn create_or_update<T, S1, S2, F, E>(
+    api: &Api<T>,
+    namespace: Option<S1>,
+    name: S2,
+    mutator: F,
+) -> Result<Outcome<T>, E>
+where
+    T: Resource + Clone + Debug + DeserializeOwned + Serialize + PartialEq + Default,
+    S1: ToString,
+    S2: AsRef<str>,
+    F: FnMut(T) -> Result<T, E>,
+    E: From<Error>,
+{
+    create_or_update_with(api, namespace, name, &Default::default(), mutator).await
+}
+
+/// Create or update a Kubernetes resource, using the provided parameters.
+pub async fn create_or_update_with<T, S1, S2, F, E>(
+    api: &Api<T>,
+    namespace: Option<S1>,
+    name: S2,
+    params: &CreateOrUpdateParams,
+    mutator: F,
+) -> Result<Outcome<T>, E>
+where
+    T: Resource + Clone + Debug + DeserializeOwned + Serialize + PartialEq + Default,
+    S1: ToString,
+    S2: AsRef<str>,
+    F: FnMut(T) -> Result<T, E>,
+    E: From<Error>,
+{
+    create_or_update_by_with(
+        api,
+        namespace,
+        name,
+        params,
+        |meta| {
+            let mut object: T = Default::default();
+            *object.meta_mut() = meta;
+            object
+        },
+        |this, that| this == that,
+        mutator,
+    )
+    .await
+}
+
+/// Create or update a Kubernetes resource, using server-side apply.
+///
+/// This works like [`create_or_update_by`], but instead of replacing the full resource, the
+/// intended state is sent as an apply patch. The field manager and the `force` flag are taken from
+/// the provided [`PatchParams`], e.g. `PatchParams::apply("my-operator").force()`.
+///
+/// The apply patch is built from the output of the `creator`, passed through the `mutator`. The
+/// mutator does **not** receive the current state of the object, as everything it returns would
+/// be claimed by the field manager. It must only set the fields the operator wants to own.
+///
+/// If the resource exists, `eq` is called with the current state and the intended state. If it
+/// returns `true`, the apply patch is skipped. Otherwise the patch gets sent, and the outcome is
+/// [`Outcome::Unchanged`] if the server didn't create a new version of the resource.
+pub async fn apply_by<T, S1, S2, C, F, E, Eq>(
+    api: &Api<T>,
+    namespace: Option<S1>,
+    name: S2,
+    params: &PatchParams,
+    creator: C,
+    eq: Eq,
+    mutator: F,
+) -> Result<Outcome<T>, E>
+where
+    T: Resource + Clone + Debug + DeserializeOwned + Serialize,
+    S1: ToString,
+    S2: AsRef<str>,
+    C: FnOnce(ObjectMeta) -> T,
+    F: FnOnce(T) -> Result<T, E>,
+    Eq: FnOnce(&T, &T) -> bool,
+    E: From<Error>,
+{
+    let object: T = creator(ObjectMeta {
+        namespace: namespace.map(|s| s.to_string()),
+        name: Some(name.as_ref().to_string()),
+        ..Default::default()
+    });
+    let object = strip_for_apply(mutator(object)?);
+
+    match api.get(name.as_ref()).await {
+        Err(Error::Api(ae)) if ae.code == 404 => {
+            log::debug!("Apply - Err(Api(404))");
+            let object = api
+                .patch(name.as_ref(), params, &Patch::Apply(object))
+                .await?;
+            Ok(Outcome::Created(object))
+        }
+        Err(e) => {
+            log::info!("Error - {}", e);
+            Err(e)?
+        }
+        Ok(current) => {
+            log::debug!("Apply - Ok(...)");
+
+            // only apply when necessary
+            if eq(&current, &object) {
+                return Ok(Outcome::Unchanged(current));
+            }
+
+            log::debug!("Apply - Changed -> applying");
+            let new_object = api
+                .patch(name.as_ref(), params, &Patch::Apply(object))
+                .await?;
+            if new_object.meta().resource_version == current.meta().resource_version {
+                Ok(Outcome::Unchanged(new_object))
+            } else {
+                Ok(Outcome::Updated(new_object, None))
+            }
+        }
+    }
+}
+
+/// Create or update a Kubernetes resource, using server-side apply.
+///
+/// The mutator receives an empty object, carrying only the name and namespace. The apply patch is
+/// always sent, see [`apply_by`] for more details.
+pub async fn apply<T, S1, S2, F, E>(
+    api: &Api<T>,
+    namespace: Option<S1>,
+    name: S2,
+    params: &PatchParams,
+    mutator: F,
+) -> Result<Outcome<T>, E>
+where
+    T: Resource + Clone + Debug + DeserializeOwned + Serialize + Default,
+    S1: ToString,
+    S2: AsRef<str>,
+    F: FnOnce(T) -> Result<T, E>,
+    E: From<Error>,
+{
+    apply_by(
+        api,
+        namespace,
+        name,
+        params,
+        |meta| {
+            let mut object: T = Default::default();
+            *object.meta_mut() = meta;
+            object
+        },
+        |_, _| false,
+        mutator,
+    )
+    .await
+}
+
+/// Create the diff between two versions of a resource.
+pub fn diff<T>(old: &T, new: &T) -> Result<Diff, Error>
+where
+    T: Serialize,
+{
+    let old = serde_json::to_value(old).map_err(Error::SerdeError)?;
+    let new = serde_json::to_value(new).map_err(Error::SerdeError)?;
+    Ok(json_patch::diff(&old, &new))
+}
+
+/// Remove the metadata which is managed by the server, and must not be part of an apply patch.
+fn strip_for_apply<T>(mut object: T) -> T
+where
+    T: Resource,
+{
+    let meta = object.meta_mut();
+    meta.managed_fields = None;
+    meta.resource_version = None;
+    meta.creation_timestamp = None;
+    meta.generation = None;
+    meta.uid = None;
+    object
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::testing::FakeServer;
+    use http::{Method, Request, Response};
+    use hyper::Body;
+    use k8s_openapi::api::core::v1::ConfigMap;
+    use kube::Client;
+    use serde_json::json;
+    use std::{
+        collections::BTreeMap,
+        convert::Infallible,
+        future::Future,
+        pin::Pin,
+        sync::{Arc, Mutex},
+        task::{Context, Poll},
+    };
+
+    fn data(value: &str) -> Option<BTreeMap<String, String>> {
+        Some([("key".to_string(), value.to_string())].into())
+    }
+
+    async fn set_data(api: &Api<ConfigMap>, value: &str) -> Result<Outcome<ConfigMap>, Error> {
+        create_or_update_by(
+            api,
+            Some("default"),
+            "foo",
+            |meta| ConfigMap {
+                metadata: meta,
+                ..Default::default()
+            },
+            |this, that| this.data == that.data,
+            |mut cm| {
+                cm.data = data(value);
+                Ok::<_, Error>(cm)
+            },
+        )
+        .await
+    }
+
+    #[test]
+    fn test_strip_for_apply() {
+        let cm = ConfigMap {
+            metadata: ObjectMeta {
+                name: Some("foo".into()),
+                namespace: Some("bar".into()),
+                resource_version: Some("123".into()),
+                uid: Some("uid".into()),
+                generation: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        let cm = strip_for_apply(cm);
+
+        assert_eq!(
+            cm.metadata,
+            ObjectMeta {
+                name: Some("foo".into()),
+                namespace: Some("bar".into()),
+                ..Default::default()
+            }
+        );
+    }
+
+    /// Records the bodies of all patch requests, before passing them on to a [`FakeServer`].
+    #[derive(Clone)]
+    struct PatchRecorder {
+        server: FakeServer,
+        patches: Arc<Mutex<Vec<serde_json::Value>>>,
+    }
+
+    impl tower::Service<Request<Body>> for PatchRecorder {
+        type Response = Response<Body>;
+        type Error = Infallible;
+        type Future = Pin<Box<dyn Future<Output = Result<Response<Body>, Infallible>> + Send>>;
+
+        fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Infallible>> {
+            self.server.poll_ready(cx)
+        }
+
+        fn call(&mut self, request: Request<Body>) -> Self::Future {
+            let mut server = self.server.clone();
+            let patches = self.patches.clone();
+            Box::pin(async move {
+                let (parts, body) = request.into_parts();
+                let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+                if parts.method == Method::PATCH {
+                    patches
+                        .lock()
+                        .unwrap()
+                        .push(serde_json::from_slice(&body).unwrap());
+                }
+                server
+                    .call(Request::from_parts(parts, Body::from(body)))
+                    .await
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn test_apply_by() {
+        let server = FakeServer::new();
+        let recorder = PatchRecorder {
+            server: server.clone(),
+            patches: Default::default(),
+        };
+        let api = Api::<ConfigMap>::namespaced(Client::new(recorder.clone(), "default"), "default");
+
+        server.insert(ConfigMap {
+            metadata: ObjectMeta {
+                name: Some("foo".into()),
+                namespace: Some("default".into()),
+                labels: Some([("other".to_string(), "manager".to_string())].into()),
+                ..Default::default()
+            },
+            data: Some([("other".to_string(), "value".to_string())].into()),
+            ..Default::default()
+        });
+
+        let params = PatchParams::apply("test");
+        let apply = |value: &'static str| {
+            apply_by(
+                &api,
+                Some("default"),
+                "foo",
+                &params,
+                |meta| ConfigMap {
+                    metadata: meta,
+                    ..Default::default()
+                },
+                |current, intended| {
+                    current.data.as_ref().and_then(|data| data.get("key"))
+                        == intended.data.as_ref().and_then(|data| data.get("key"))
+                },
+                move |mut cm| {
+                    cm.data = data(value);
+                    Ok::<_, Error>(cm)
+                },
+            )
+        };
+
+        let outcome = apply("foo").await.unwrap();
+        assert!(matches!(outcome, Outcome::Updated(_, None)));
+
+        // only the fields set by the creator and mutator are part of the apply patch
+        assert_eq!(
+            recorder.patches.lock().unwrap().as_slice(),
+            &[json!({
+                "apiVersion": "v1",
+                "kind": "ConfigMap",
+                "metadata": {
+                    "name": "foo",
+                    "namespace": "default",
+                },
+                "data": {
+                    "key": "foo",
+                },
+            })]
+        );
+
+        let outcome = apply("foo").await.unwrap();
+        assert!(matches!(outcome, Outcome::Unchanged(_)));
+        assert_eq!(recorder.patches.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = ConfigMap {
+            data: Some([("foo".to_string(), "bar".to_string())].into()),
+            ..Default::default()
+        };
+        let new = ConfigMap {
+            data: Some([("foo".to_string(), "baz".to_string())].into()),
+            ..Default::default()
+        };
+
+        let diff = diff(&old, &new).unwrap();
+
+        assert_eq!(
+            serde_json::to_value(diff).unwrap(),
+            json!([{"op": "replace", "path": "/data/foo", "value": "baz"}])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_or_update_by() {
+        let server = FakeServer::new();
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+
+        let outcome = set_data(&api, "foo").await.unwrap();
+        assert!(matches!(outcome, Outcome::Created(_)));
+        let created = server.get::<ConfigMap>(Some("default"), "foo").unwrap();
+        assert_eq!(created.data, data("foo"));
+
+        let outcome = set_data(&api, "foo").await.unwrap();
+        assert!(matches!(outcome, Outcome::Unchanged(_)));
+        assert_eq!(
+            server.get::<ConfigMap>(Some("default"), "foo"),
+            Some(created.clone())
+        );
+
+        let outcome = set_data(&api, "bar").await.unwrap();
+        assert!(matches!(outcome, Outcome::Updated(_, None)));
+        let updated = server.get::<ConfigMap>(Some("default"), "foo").unwrap();
+        assert_eq!(updated.data, data("bar"));
+        assert_eq!(updated.metadata.uid, created.metadata.uid);
+        assert_ne!(
+            updated.metadata.resource_version,
+            created.metadata.resource_version
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_or_update_by_conflict() {
+        let server = FakeServer::new();
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+        set_data(&api, "foo").await.unwrap();
+
+        // modify the resource in the meantime, during the first attempt only
+        let mut attempts = 0;
+        let outcome = create_or_update_by(
+            &api,
+            Some("default"),
+            "foo",
+            |meta| ConfigMap {
+                metadata: meta,
+                ..Default::default()
+            },
+            |this, that| this.data == that.data,
+            |mut cm| {
+                attempts += 1;
+                if attempts == 1 {
+                    server.insert(cm.clone());
+                }
+                cm.data = data("bar");
+                Ok::<_, Error>(cm)
+            },
+        )
+        .await
+        .unwrap();
+
+        assert!(matches!(outcome, Outcome::Updated(_, None)));
+        assert_eq!(attempts, 2);
+        assert_eq!(
+            server
+                .get::<ConfigMap>(Some("default"), "foo")
+                .unwrap()
+                .data,
+            data("bar")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_create_or_update_dry_run() {
+        let server = FakeServer::new();
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+
+        for dry_run in [DryRun::Server, DryRun::Client] {
+            let outcome = create_or_update_with(
+                &api,
+                Some("default"),
+                "foo",
+                &CreateOrUpdateParams {
+                    dry_run,
+                    ..Default::default()
+                },
+                |mut cm: ConfigMap| {
+                    cm.data = data("foo");
+                    Ok::<_, Error>(cm)
+                },
+            )
+            .await
+            .unwrap();
+
+            assert!(matches!(outcome, Outcome::Created(_)));
+            assert!(server.get::<ConfigMap>(Some("default"), "foo").is_none());
+        }
+    }
+}
@@ -0,0 +1,162 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+use kube::Error;
+use rand::Rng;
+use std::time::Duration;
+
+/// A policy for retrying operations which failed due to a conflict.
+///
+/// The backoff starts with `initial_backoff` and grows by `multiplier` with each attempt, until it
+/// reaches `max_backoff`. A random jitter of up to `jitter` (as fraction of the backoff) is
+/// added or subtracted, to prevent multiple instances from retrying in lockstep.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the initial one.
+    pub max_attempts: usize,
+    /// The backoff before the first retry.
+    pub initial_backoff: Duration,
+    /// The upper limit of the backoff.
+    pub max_backoff: Duration,
+    /// The factor by which the backoff grows with each attempt.
+    pub multiplier: f64,
+    /// The jitter, as fraction of the backoff, between `0.0` and `1.0`.
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            multiplier: 2.0,
+            jitter: 0.2,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// A policy which never retries.
+    pub fn never() -> Self {
+        Self {
+            max_attempts: 1,
+            ..Default::default()
+        }
+    }
+
+    /// Check if another attempt should be made, after `attempt` (starting with `1`) failed.
+    pub fn should_retry(&self, attempt: usize) -> bool {
+        attempt < self.max_attempts
+    }
+
+    /// The backoff after the failed `attempt` (starting with `1`), without jitter.
+    pub fn backoff(&self, attempt: usize) -> Duration {
+        let exp = attempt.saturating_sub(1).min(i32::MAX as usize) as i32;
+        let backoff = self.initial_backoff.as_secs_f64() * self.multiplier.max(1.0).powi(exp);
+        Duration::from_secs_f64(backoff.min(self.max_backoff.as_secs_f64()))
+    }
+
+    /// The backoff after the failed `attempt` (starting with `1`), including a random jitter.
+    pub fn backoff_with_jitter(&self, attempt: usize) -> Duration {
+        let backoff = self.backoff(attempt);
+        let jitter = self.jitter.clamp(0.0, 1.0);
+        if jitter > 0.0 {
+            let factor = rand::thread_rng().gen_range(-jitter..=jitter);
+            backoff.mul_f64(1.0 + factor)
+        } else {
+            backoff
+        }
+    }
+
+    /// Wait for the backoff after the failed `attempt`.
+    pub(crate) async fn wait(&self, attempt: usize) {
+        let backoff = self.backoff_with_jitter(attempt);
+        log::debug!("Retrying after {:?} (attempt {})", backoff, attempt);
+        tokio::time::sleep(backoff).await;
+    }
+}
+
+/// Check if the error is a conflict, which may be resolved by re-trying the operation.
+///
+/// This covers a `Conflict` when updating an outdated resource, as well as `AlreadyExists` when
+/// another party created the resource in the meantime.
+pub fn is_conflict(err: &Error) -> bool {
+    matches!(err, Error::Api(ae) if ae.code == 409)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use kube::error::ErrorResponse;
+
+    #[test]
+    fn test_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+            multiplier: 2.0,
+            jitter: 0.0,
+        };
+
+        assert_eq!(policy.backoff(1), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(200));
+        assert_eq!(policy.backoff(3), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_millis(800));
+        assert_eq!(policy.backoff(5), Duration::from_secs(1));
+        assert_eq!(policy.backoff(100), Duration::from_secs(1));
+        assert_eq!(policy.backoff_with_jitter(2), Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_jitter() {
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..Default::default()
+        };
+
+        for _ in 0..100 {
+            let backoff = policy.backoff_with_jitter(1);
+            assert!(backoff >= Duration::from_millis(50));
+            assert!(backoff <= Duration::from_millis(150));
+        }
+    }
+
+    #[test]
+    fn test_should_retry() {
+        let policy = RetryPolicy::default();
+        assert!(policy.should_retry(1));
+        assert!(policy.should_retry(4));
+        assert!(!policy.should_retry(5));
+
+        assert!(!RetryPolicy::never().should_retry(1));
+    }
+
+    #[test]
+    fn test_is_conflict() {
+        let err = |code, reason: &str| {
+            Error::Api(ErrorResponse {
+                status: "Failure".into(),
+                message: String::new(),
+                reason: reason.into(),
+                code,
+            })
+        };
+
+        assert!(is_conflict(&err(409, "Conflict")));
+        assert!(is_conflict(&err(409, "AlreadyExists")));
+        assert!(!is_conflict(&err(404, "NotFound")));
+    }
+}
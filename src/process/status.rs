@@ -0,0 +1,330 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+use super::{diff, is_conflict, CreateOrUpdateParams, DryRun, Failure, Outcome};
+use crate::conditions::{Conditions, HasConditions, StateDetails};
+use kube::{core::object::HasStatus, Api, Error, Resource};
+use serde::{de::DeserializeOwned, Serialize};
+use std::fmt::Debug;
+
+/// Update the status of a Kubernetes resource.
+///
+/// This uses the default [`CreateOrUpdateParams`], see [`update_status_with`] for more details.
+pub async fn update_status<T, S, F, E>(api: &Api<T>, name: S, mutator: F) -> Result<Outcome<T>, E>
+where
+    T: Resource + HasStatus + Clone + Debug + DeserializeOwned + Serialize,
+    T::Status: Clone + Default + PartialEq,
+    S: AsRef<str>,
+    F: FnMut(&mut T::Status) -> Result<(), E>,
+    E: From<Error>,
+{
+    update_status_with(api, name, &Default::default(), mutator).await
+}
+
+/// Update the status of a Kubernetes resource.
+///
+/// The resource is fetched, and the mutator is run on its status (which gets created if it is
+/// missing). Only if the status changed, it is written using the status subresource.
+///
+/// The written object carries the `resourceVersion` of the fetched object. If the resource was
+/// modified in the meantime, the operation is re-tried according to the retry policy of the
+/// provided parameters, re-fetching the resource and running the mutator again.
+pub async fn update_status_with<T, S, F, E>(
+    api: &Api<T>,
+    name: S,
+    params: &CreateOrUpdateParams,
+    mut mutator: F,
+) -> Result<Outcome<T>, E>
+where
+    T: Resource + HasStatus + Clone + Debug + DeserializeOwned + Serialize,
+    T::Status: Clone + Default + PartialEq,
+    S: AsRef<str>,
+    F: FnMut(&mut T::Status) -> Result<(), E>,
+    E: From<Error>,
+{
+    let mut attempt = 0;
+
+    loop {
+        attempt += 1;
+
+        match update_status_once(api, params, name.as_ref(), &mut mutator).await {
+            Ok(outcome) => return Ok(outcome),
+            Err(Failure::Api(err)) if is_conflict(&err) && params.retry.should_retry(attempt) => {
+                log::debug!("UpdateStatus - Conflict: {}", err);
+                params.retry.wait(attempt).await;
+            }
+            Err(Failure::Api(err)) => return Err(err.into()),
+            Err(Failure::Other(err)) => return Err(err),
+        }
+    }
+}
+
+/// A single attempt of [`update_status_with`].
+async fn update_status_once<T, F, E>(
+    api: &Api<T>,
+    params: &CreateOrUpdateParams,
+    name: &str,
+    mutator: &mut F,
+) -> Result<Outcome<T>, Failure<E>>
+where
+    T: Resource + HasStatus + Clone + Debug + DeserializeOwned + Serialize,
+    T::Status: Clone + Default + PartialEq,
+    F: FnMut(&mut T::Status) -> Result<(), E>,
+{
+    let object = api.get(name).await.map_err(Failure::Api)?;
+
+    let mut new_object = object.clone();
+    let mut status = new_object.status().cloned().unwrap_or_default();
+    mutator(&mut status).map_err(Failure::Other)?;
+
+    // only update when necessary
+    if object.status() == Some(&status) {
+        return Ok(Outcome::Unchanged(object));
+    }
+
+    log::debug!("UpdateStatus - Changed -> replacing");
+    new_object.status_mut().replace(status);
+
+    let diff = match params.diff {
+        true => Some(diff(&object, &new_object).map_err(Failure::Api)?),
+        false => None,
+    };
+
+    if params.dry_run == DryRun::Client {
+        return Ok(Outcome::Updated(new_object, diff));
+    }
+
+    let data = serde_json::to_vec(&new_object)
+        .map_err(Error::SerdeError)
+        .map_err(Failure::Api)?;
+    let new_object = api
+        .replace_status(name, &params.dry_run.post_params(), data)
+        .await
+        .map_err(Failure::Api)?;
+
+    Ok(Outcome::Updated(new_object, diff))
+}
+
+/// Update a condition in the status of a Kubernetes resource.
+///
+/// This is a shortcut to [`update_status`], for statuses providing access to their conditions.
+///
+/// Note: conditions which track the last probe time will always result in an update.
+pub async fn update_condition<T, S1, S2, D>(
+    api: &Api<T>,
+    name: S1,
+    r#type: S2,
+    state: D,
+) -> Result<Outcome<T>, Error>
+where
+    T: Resource + HasStatus + Clone + Debug + DeserializeOwned + Serialize,
+    T::Status: HasConditions + Clone + Default + PartialEq,
+    S1: AsRef<str>,
+    S2: AsRef<str>,
+    D: Into<StateDetails>,
+{
+    let state = state.into();
+    update_status(api, name, |status: &mut T::Status| {
+        status
+            .conditions_mut()
+            .update_condition(r#type.as_ref(), state.clone());
+        Ok::<_, Error>(())
+    })
+    .await
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::{conditions::State, process::RetryPolicy, testing::FakeServer};
+    use chrono::{TimeZone, Utc};
+    use k8s_openapi::{api::batch::v1::JobCondition, apimachinery::pkg::apis::meta::v1::Time};
+    use kube::{api::ObjectMeta, CustomResource, ResourceExt};
+    use serde_derive::{Deserialize, Serialize};
+
+    #[derive(CustomResource, Clone, Debug, Default, Deserialize, Serialize)]
+    #[kube(
+        group = "example.com",
+        version = "v1",
+        kind = "Example",
+        namespaced,
+        status = "ExampleStatus",
+        schema = "disabled"
+    )]
+    pub struct ExampleSpec {}
+
+    #[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+    pub struct ExampleStatus {
+        observed_generation: Option<i64>,
+        conditions: Option<Vec<JobCondition>>,
+    }
+
+    impl HasConditions for ExampleStatus {
+        type Conditions = Option<Vec<JobCondition>>;
+
+        fn conditions_mut(&mut self) -> &mut Self::Conditions {
+            &mut self.conditions
+        }
+    }
+
+    fn setup(status: Option<ExampleStatus>) -> (FakeServer, Api<Example>, Example) {
+        let server = FakeServer::new();
+        let api = Api::<Example>::namespaced(server.client(), "default");
+        let example = server.insert(Example {
+            metadata: ObjectMeta {
+                name: Some("foo".into()),
+                namespace: Some("default".into()),
+                ..Default::default()
+            },
+            spec: ExampleSpec {},
+            status,
+        });
+        (server, api, example)
+    }
+
+    fn stored(server: &FakeServer) -> Example {
+        server.get::<Example>(Some("default"), "foo").unwrap()
+    }
+
+    fn set_generation(generation: i64) -> impl FnMut(&mut ExampleStatus) -> Result<(), Error> {
+        move |status| {
+            status.observed_generation = Some(generation);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_status() {
+        let (server, api, example) = setup(None);
+
+        let outcome = update_status(&api, "foo", set_generation(1)).await.unwrap();
+        assert!(matches!(outcome, Outcome::Updated(_, None)));
+        let updated = stored(&server);
+        assert_ne!(updated.resource_version(), example.resource_version());
+        assert_eq!(updated.status.unwrap().observed_generation, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_update_status_unchanged() {
+        let (server, api, example) = setup(Some(ExampleStatus {
+            observed_generation: Some(1),
+            conditions: None,
+        }));
+
+        let outcome = update_status(&api, "foo", set_generation(1)).await.unwrap();
+        assert!(matches!(outcome, Outcome::Unchanged(_)));
+        // nothing was written
+        assert_eq!(
+            stored(&server).resource_version(),
+            example.resource_version()
+        );
+    }
+
+    #[tokio::test]
+    async fn test_update_status_conflict() {
+        let (server, api, _) = setup(None);
+
+        // modify the resource during the first attempt
+        let mut attempts = 0;
+        let outcome = update_status(&api, "foo", |status: &mut ExampleStatus| {
+            attempts += 1;
+            if attempts == 1 {
+                server.insert(stored(&server));
+            }
+            status.observed_generation = Some(1);
+            Ok::<_, Error>(())
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(attempts, 2);
+        assert!(matches!(outcome, Outcome::Updated(_, None)));
+        assert_eq!(stored(&server).status.unwrap().observed_generation, Some(1));
+
+        // without re-trying, the conflict is reported
+        let params = CreateOrUpdateParams {
+            retry: RetryPolicy::never(),
+            ..Default::default()
+        };
+        let result = update_status_with(&api, "foo", &params, |status: &mut ExampleStatus| {
+            server.insert(stored(&server));
+            status.observed_generation = Some(2);
+            Ok::<_, Error>(())
+        })
+        .await;
+        assert!(matches!(result, Err(Error::Api(err)) if err.code == 409));
+        assert_eq!(stored(&server).status.unwrap().observed_generation, Some(1));
+    }
+
+    #[tokio::test]
+    async fn test_update_status_dry_run() {
+        for dry_run in [DryRun::Server, DryRun::Client] {
+            let (server, api, example) = setup(None);
+            let params = CreateOrUpdateParams {
+                dry_run,
+                diff: true,
+                ..Default::default()
+            };
+
+            let outcome = update_status_with(&api, "foo", &params, set_generation(1))
+                .await
+                .unwrap();
+            match outcome {
+                Outcome::Updated(updated, Some(diff)) => {
+                    assert_eq!(updated.status.unwrap().observed_generation, Some(1));
+                    assert!(!diff.0.is_empty());
+                }
+                _ => panic!("Expected an update, including a diff"),
+            }
+
+            let stored = stored(&server);
+            assert_eq!(stored.resource_version(), example.resource_version());
+            assert_eq!(stored.status, None);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_update_condition() {
+        let long_ago = Time(Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap());
+        let (server, api, _) = setup(Some(ExampleStatus {
+            observed_generation: None,
+            conditions: Some(vec![JobCondition {
+                type_: "Ready".into(),
+                status: "True".into(),
+                last_transition_time: Some(long_ago.clone()),
+                ..Default::default()
+            }]),
+        }));
+
+        let transition_time = |server: &FakeServer| {
+            stored(server).status.unwrap().conditions.unwrap()[0]
+                .last_transition_time
+                .clone()
+        };
+
+        // same state, keeps the transition time
+        update_condition(&api, "foo", "Ready", State::True)
+            .await
+            .unwrap();
+        assert_eq!(transition_time(&server), Some(long_ago.clone()));
+
+        // changed state, updates the transition time
+        update_condition(&api, "foo", "Ready", State::False)
+            .await
+            .unwrap();
+        let condition = &stored(&server).status.unwrap().conditions.unwrap()[0];
+        assert_eq!(condition.status, "False");
+        assert_ne!(condition.last_transition_time, Some(long_ago));
+    }
+}
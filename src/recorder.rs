@@ -0,0 +1,589 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+//! Record Kubernetes events.
+//!
+//! Events are created using the `core/v1` API, which is the one shown by `kubectl describe`.
+//!
+//! Identical events for the same object are aggregated into a series: instead of creating a new
+//! event, the count, the last timestamp, and the series of the existing event are updated.
+//!
+//! In order to not flood the API server, events are rate limited per object. Events exceeding the
+//! limit are dropped.
+
+use crate::{
+    conditions::{State, Transition},
+    install::meta::{AsObjectReference, Meta},
+};
+use chrono::{DateTime, Utc};
+use k8s_openapi::{
+    api::core::v1::{Event, EventSource, ObjectReference},
+    apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta, Time},
+};
+use kube::{
+    api::{Patch, PatchParams, PostParams},
+    Api, Client,
+};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    hash::Hash,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// The maximum number of events, or objects, tracked by the recorder.
+const MAX_CACHE_ENTRIES: usize = 4096;
+
+/// The type of an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum EventType {
+    Normal,
+    Warning,
+}
+
+impl Display for EventType {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Normal => write!(f, "Normal"),
+            Self::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+/// A per-object rate limit of events.
+///
+/// Each object may record up to `burst` events at once, afterwards one more event is allowed
+/// for each `interval`. The defaults match the ones of the Kubernetes Go client.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub interval: Duration,
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self {
+            burst: 25,
+            interval: Duration::from_secs(300),
+        }
+    }
+}
+
+/// Identifies the involved object.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct ObjectKey {
+    kind: Option<String>,
+    namespace: Option<String>,
+    name: Option<String>,
+    uid: Option<String>,
+}
+
+impl From<&ObjectReference> for ObjectKey {
+    fn from(object: &ObjectReference) -> Self {
+        Self {
+            kind: object.kind.clone(),
+            namespace: object.namespace.clone(),
+            name: object.name.clone(),
+            uid: object.uid.clone(),
+        }
+    }
+}
+
+/// Identifies identical events.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct EventKey {
+    object: ObjectKey,
+    event_type: EventType,
+    reason: String,
+    message: String,
+}
+
+/// An event which was already recorded.
+#[derive(Clone, Debug)]
+struct Recorded {
+    namespace: String,
+    name: String,
+    count: i32,
+    last_timestamp: DateTime<Utc>,
+}
+
+/// A token bucket, per object.
+#[derive(Clone, Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+#[derive(Debug)]
+struct RateLimiter {
+    limit: RateLimit,
+    buckets: HashMap<ObjectKey, Bucket>,
+}
+
+impl RateLimiter {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            buckets: Default::default(),
+        }
+    }
+
+    /// Try to take a token for the object, returning `false` if the object exceeded its limit.
+    fn try_acquire(&mut self, key: &ObjectKey, now: Instant) -> bool {
+        let burst = self.limit.burst as f64;
+        let interval = self.limit.interval.as_secs_f64();
+
+        if !self.buckets.contains_key(key) {
+            evict(&mut self.buckets, |b| b.last);
+        }
+
+        let bucket = self.buckets.entry(key.clone()).or_insert(Bucket {
+            tokens: burst,
+            last: now,
+        });
+
+        if interval > 0.0 {
+            let elapsed = now.saturating_duration_since(bucket.last).as_secs_f64();
+            bucket.tokens = (bucket.tokens + elapsed / interval).min(burst);
+        } else {
+            bucket.tokens = burst;
+        }
+        bucket.last = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Make room for a new entry, by evicting the least recently used one, if the map is full.
+fn evict<K, V, T, F>(map: &mut HashMap<K, V>, last_used: F)
+where
+    K: Clone + Eq + Hash,
+    T: Ord,
+    F: Fn(&V) -> T,
+{
+    if map.len() < MAX_CACHE_ENTRIES {
+        return;
+    }
+    if let Some(oldest) = map
+        .iter()
+        .min_by_key(|(_, v)| last_used(v))
+        .map(|(k, _)| k.clone())
+    {
+        map.remove(&oldest);
+    }
+}
+
+/// Records events for Kubernetes resources.
+///
+/// ```ignore
+/// let recorder = Recorder::new(client, "my-operator");
+/// recorder.normal(&resource, "Deployed", "Created the deployment").await?;
+/// ```
+///
+/// The recorder can be cloned, clones share the state used for aggregating and rate limiting
+/// events.
+#[derive(Clone)]
+pub struct Recorder {
+    client: Client,
+    component: String,
+    instance: Option<String>,
+    recorded: Arc<Mutex<HashMap<EventKey, Recorded>>>,
+    limiter: Option<Arc<Mutex<RateLimiter>>>,
+}
+
+impl Recorder {
+    /// Create a new recorder, reporting events as the provided component (e.g. the name of the
+    /// operator).
+    ///
+    /// The recorder uses the default [`RateLimit`].
+    pub fn new<S>(client: Client, component: S) -> Self
+    where
+        S: Into<String>,
+    {
+        Self {
+            client,
+            component: component.into(),
+            instance: None,
+            recorded: Default::default(),
+            limiter: Some(Arc::new(Mutex::new(RateLimiter::new(Default::default())))),
+        }
+    }
+
+    /// Set the instance of the component reporting the events, e.g. the name of the pod.
+    pub fn with_instance<S>(mut self, instance: S) -> Self
+    where
+        S: Into<String>,
+    {
+        self.instance = Some(instance.into());
+        self
+    }
+
+    /// Set the per-object rate limit, `None` disables rate limiting.
+    pub fn with_rate_limit<R>(mut self, limit: R) -> Self
+    where
+        R: Into<Option<RateLimit>>,
+    {
+        self.limiter = limit
+            .into()
+            .map(|limit| Arc::new(Mutex::new(RateLimiter::new(limit))));
+        self
+    }
+
+    /// Record a normal event for a resource.
+    pub async fn normal<K, S1, S2>(
+        &self,
+        resource: &K,
+        reason: S1,
+        message: S2,
+    ) -> Result<(), kube::Error>
+    where
+        K: Meta,
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        self.record(resource, EventType::Normal, reason, message)
+            .await
+    }
+
+    /// Record a warning event for a resource.
+    pub async fn warning<K, S1, S2>(
+        &self,
+        resource: &K,
+        reason: S1,
+        message: S2,
+    ) -> Result<(), kube::Error>
+    where
+        K: Meta,
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        self.record(resource, EventType::Warning, reason, message)
+            .await
+    }
+
+    /// Record an event for a resource.
+    ///
+    /// If the event exceeds the rate limit of the resource, it is dropped.
+    pub async fn record<K, S1, S2>(
+        &self,
+        resource: &K,
+        event_type: EventType,
+        reason: S1,
+        message: S2,
+    ) -> Result<(), kube::Error>
+    where
+        K: Meta,
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        self.record_for(resource.as_object_reference(), event_type, reason, message)
+            .await
+    }
+
+    /// Record an event for a condition transition.
+    ///
+    /// Transitions to `False` are recorded as warnings, all others as normal events. The reason of
+    /// the event is the reason of the condition, or its type if the condition has no reason.
+    pub async fn record_transition<K>(
+        &self,
+        resource: &K,
+        transition: &Transition,
+    ) -> Result<(), kube::Error>
+    where
+        K: Meta,
+    {
+        let (event_type, reason, message) = transition_event(transition);
+        self.record(resource, event_type, reason, message).await
+    }
+
+    async fn record_for<S1, S2>(
+        &self,
+        object: ObjectReference,
+        event_type: EventType,
+        reason: S1,
+        message: S2,
+    ) -> Result<(), kube::Error>
+    where
+        S1: Into<String>,
+        S2: Into<String>,
+    {
+        let key = EventKey {
+            object: ObjectKey::from(&object),
+            event_type,
+            reason: reason.into(),
+            message: message.into(),
+        };
+
+        if let Some(limiter) = &self.limiter {
+            if !limiter
+                .lock()
+                .unwrap()
+                .try_acquire(&key.object, Instant::now())
+            {
+                log::debug!("Dropping event, rate limit exceeded: {:?}", key);
+                return Ok(());
+            }
+        }
+
+        let now = Utc::now();
+        let recorded = self.recorded.lock().unwrap().get(&key).cloned();
+
+        if let Some(recorded) = recorded {
+            match self.increment(&recorded, now).await {
+                Ok(()) => {
+                    self.remember(
+                        key,
+                        Recorded {
+                            count: recorded.count + 1,
+                            last_timestamp: now,
+                            ..recorded
+                        },
+                    );
+                    return Ok(());
+                }
+                // the event expired, create a new one
+                Err(kube::Error::Api(err)) if err.code == 404 => {
+                    log::debug!("Event {} expired, creating a new one", recorded.name);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+
+        let event = self.new_event(object, &key, now);
+        let namespace = event.metadata.namespace.clone().unwrap_or_default();
+        let event = Api::<Event>::namespaced(self.client.clone(), &namespace)
+            .create(&PostParams::default(), &event)
+            .await?;
+
+        self.remember(
+            key,
+            Recorded {
+                namespace,
+                name: event.metadata.name.unwrap_or_default(),
+                count: 1,
+                last_timestamp: now,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Add another occurrence to the series of an existing event.
+    async fn increment(&self, recorded: &Recorded, now: DateTime<Utc>) -> Result<(), kube::Error> {
+        let count = recorded.count + 1;
+        let patch = json!({
+            "count": count,
+            "lastTimestamp": Time(now),
+            // not using `EventSeries`, its fields depend on the API version
+            "series": {
+                "count": count,
+                "lastObservedTime": MicroTime(now),
+            },
+        });
+        Api::<Event>::namespaced(self.client.clone(), &recorded.namespace)
+            .patch(
+                &recorded.name,
+                &PatchParams::default(),
+                &Patch::Merge(&patch),
+            )
+            .await?;
+        Ok(())
+    }
+
+    fn remember(&self, key: EventKey, recorded: Recorded) {
+        let mut events = self.recorded.lock().unwrap();
+        if !events.contains_key(&key) {
+            evict(&mut events, |r| r.last_timestamp);
+        }
+        events.insert(key, recorded);
+    }
+
+    fn new_event(&self, object: ObjectReference, key: &EventKey, now: DateTime<Utc>) -> Event {
+        // cluster scoped resources get their events in the default namespace
+        let namespace = object
+            .namespace
+            .clone()
+            .unwrap_or_else(|| "default".to_string());
+        let name = format!(
+            "{}.{:x}",
+            object.name.as_deref().unwrap_or_default(),
+            now.timestamp_nanos_opt().unwrap_or_default()
+        );
+
+        Event {
+            metadata: ObjectMeta {
+                name: Some(name),
+                namespace: Some(namespace),
+                ..Default::default()
+            },
+            involved_object: object,
+            type_: Some(key.event_type.to_string()),
+            reason: Some(key.reason.clone()),
+            message: Some(key.message.clone()),
+            count: Some(1),
+            first_timestamp: Some(Time(now)),
+            last_timestamp: Some(Time(now)),
+            source: Some(EventSource {
+                component: Some(self.component.clone()),
+                host: None,
+            }),
+            reporting_component: Some(self.component.clone()),
+            reporting_instance: self.instance.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// The type, reason and message of an event for a condition transition.
+fn transition_event(transition: &Transition) -> (EventType, String, String) {
+    let event_type = match transition.to {
+        State::False => EventType::Warning,
+        State::True | State::Unknown => EventType::Normal,
+    };
+
+    let reason = transition
+        .reason
+        .clone()
+        .filter(|reason| !reason.is_empty())
+        .unwrap_or_else(|| transition.r#type.clone());
+
+    let mut message = match transition.from {
+        Some(from) => format!(
+            "Condition '{}' changed from {} to {}",
+            transition.r#type, from, transition.to
+        ),
+        None => format!("Condition '{}' set to {}", transition.r#type, transition.to),
+    };
+    if let Some(details) = transition.message.as_ref().filter(|m| !m.is_empty()) {
+        message.push_str(": ");
+        message.push_str(details);
+    }
+
+    (event_type, reason, message)
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_transition_event() {
+        let mut transition = Transition {
+            r#type: "Ready".into(),
+            from: None,
+            to: State::True,
+            reason: None,
+            message: None,
+        };
+
+        assert_eq!(
+            transition_event(&transition),
+            (
+                EventType::Normal,
+                "Ready".into(),
+                "Condition 'Ready' set to True".into()
+            )
+        );
+
+        transition.from = Some(State::True);
+        transition.to = State::False;
+        transition.reason = Some("DeploymentFailed".into());
+        transition.message = Some("Deployment is not available".into());
+
+        assert_eq!(
+            transition_event(&transition),
+            (
+                EventType::Warning,
+                "DeploymentFailed".into(),
+                "Condition 'Ready' changed from True to False: Deployment is not available".into()
+            )
+        );
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut limiter = RateLimiter::new(RateLimit {
+            burst: 2,
+            interval: Duration::from_secs(10),
+        });
+        let key = |name: &str| ObjectKey {
+            kind: Some("ConfigMap".into()),
+            namespace: Some("default".into()),
+            name: Some(name.into()),
+            uid: None,
+        };
+        let foo = key("foo");
+        let bar = key("bar");
+        let now = Instant::now();
+
+        assert!(limiter.try_acquire(&foo, now));
+        assert!(limiter.try_acquire(&foo, now));
+        assert!(!limiter.try_acquire(&foo, now));
+
+        // other objects have their own limit
+        assert!(limiter.try_acquire(&bar, now));
+
+        // refill a single token
+        assert!(!limiter.try_acquire(&foo, now + Duration::from_secs(5)));
+        assert!(limiter.try_acquire(&foo, now + Duration::from_secs(11)));
+        assert!(!limiter.try_acquire(&foo, now + Duration::from_secs(11)));
+
+        // never exceeds the burst
+        let later = now + Duration::from_secs(1000);
+        assert!(limiter.try_acquire(&foo, later));
+        assert!(limiter.try_acquire(&foo, later));
+        assert!(!limiter.try_acquire(&foo, later));
+    }
+
+    #[test]
+    fn test_evict() {
+        let mut map: HashMap<usize, usize> = (0..MAX_CACHE_ENTRIES).map(|i| (i, i + 10)).collect();
+        evict(&mut map, |v| *v);
+        assert_eq!(map.len(), MAX_CACHE_ENTRIES - 1);
+        assert!(!map.contains_key(&0));
+    }
+
+    #[tokio::test]
+    async fn test_series() {
+        use crate::testing::{config_map, FakeServer};
+
+        let server = FakeServer::new();
+        let cm = server.insert(config_map("foo").build());
+        let recorder = Recorder::new(server.client(), "test");
+
+        recorder
+            .normal(&cm, "Test", "Something happened")
+            .await
+            .unwrap();
+        recorder
+            .normal(&cm, "Test", "Something happened")
+            .await
+            .unwrap();
+
+        let events = server.list::<Event>(Some("default"));
+        assert_eq!(events.len(), 1);
+        let event = &events[0];
+        assert_eq!(event.count, Some(2));
+        let series = event.series.as_ref().unwrap();
+        assert_eq!(series.count, Some(2));
+        assert!(series.last_observed_time.is_some());
+    }
+}
@@ -0,0 +1,158 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+use super::ToSelector;
+
+/// The operator of a field selector requirement.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldOperator {
+    /// `=`
+    Equal,
+    /// `==`
+    DoubleEqual,
+    /// `!=`
+    NotEqual,
+}
+
+impl FieldOperator {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Equal => "=",
+            Self::DoubleEqual => "==",
+            Self::NotEqual => "!=",
+        }
+    }
+}
+
+/// A builder for field selectors.
+///
+/// ```
+/// use operator_framework::selectors::{FieldSelector, ToSelector};
+///
+/// let selector = FieldSelector::new()
+///     .equal("spec.nodeName", "node-1")
+///     .not_equal("status.phase", "Running");
+///
+/// assert_eq!("spec.nodeName=node-1,status.phase!=Running", selector.to_selector());
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FieldSelector(Vec<(String, FieldOperator, String)>);
+
+impl FieldSelector {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add a requirement to the selector.
+    pub fn with<F, V>(mut self, field: F, operator: FieldOperator, value: V) -> Self
+    where
+        F: Into<String>,
+        V: Into<String>,
+    {
+        self.0.push((field.into(), operator, value.into()));
+        self
+    }
+
+    /// Require the field to be equal to the value (`field=value`).
+    pub fn equal<F, V>(self, field: F, value: V) -> Self
+    where
+        F: Into<String>,
+        V: Into<String>,
+    {
+        self.with(field, FieldOperator::Equal, value)
+    }
+
+    /// Require the field to be equal to the value (`field==value`).
+    pub fn double_equal<F, V>(self, field: F, value: V) -> Self
+    where
+        F: Into<String>,
+        V: Into<String>,
+    {
+        self.with(field, FieldOperator::DoubleEqual, value)
+    }
+
+    /// Require the field to be not equal to the value (`field!=value`).
+    pub fn not_equal<F, V>(self, field: F, value: V) -> Self
+    where
+        F: Into<String>,
+        V: Into<String>,
+    {
+        self.with(field, FieldOperator::NotEqual, value)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl ToSelector for FieldSelector {
+    /// All requirements, joined to an "and" expression, with their values being escaped.
+    fn to_selector(&self) -> String {
+        self.0
+            .iter()
+            .map(|(field, op, value)| format!("{}{}{}", field, op.as_str(), escape_value(value)))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+/// Escape a field selector value.
+///
+/// Backslashes, commas and equal signs carry a meaning in a field selector, and so must be
+/// prefixed with a backslash when being part of a value.
+pub fn escape_value(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '\\' | ',' | '=') {
+            result.push('\\');
+        }
+        result.push(c);
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_empty() {
+        assert_eq!("", FieldSelector::new().to_selector());
+    }
+
+    #[test]
+    fn test_operators() {
+        let selector = FieldSelector::new()
+            .equal("metadata.name", "foo")
+            .double_equal("metadata.namespace", "bar")
+            .not_equal("status.phase", "Running");
+
+        assert_eq!(
+            "metadata.name=foo,metadata.namespace==bar,status.phase!=Running",
+            selector.to_selector()
+        );
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!("foo", escape_value("foo"));
+        assert_eq!(r"a\,b\=c\\d", escape_value(r"a,b=c\d"));
+        assert_eq!(
+            r"involvedObject.name=a\,b",
+            FieldSelector::new()
+                .equal("involvedObject.name", "a,b")
+                .to_selector()
+        );
+    }
+}
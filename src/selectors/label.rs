@@ -0,0 +1,321 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+use super::{validate_key, validate_value, Error, ToSelector};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{LabelSelector, LabelSelectorRequirement};
+use std::collections::BTreeMap;
+
+/// A single expression of a label selector.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Expression {
+    /// `key=value`
+    Equal(String, String),
+    /// `key!=value`
+    NotEqual(String, String),
+    /// `key in (value1,value2)`
+    In(String, Vec<String>),
+    /// `key notin (value1,value2)`
+    NotIn(String, Vec<String>),
+    /// `key`
+    Exists(String),
+    /// `!key`
+    DoesNotExist(String),
+}
+
+impl Expression {
+    /// The key the expression refers to.
+    pub fn key(&self) -> &str {
+        match self {
+            Self::Equal(key, _)
+            | Self::NotEqual(key, _)
+            | Self::In(key, _)
+            | Self::NotIn(key, _)
+            | Self::Exists(key)
+            | Self::DoesNotExist(key) => key,
+        }
+    }
+
+    /// Check if the expression matches the provided labels.
+    ///
+    /// Negative expressions (`!=` and `notin`) match if the label is missing.
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        let label = labels.get(self.key());
+        match self {
+            Self::Equal(_, value) => label == Some(value),
+            Self::NotEqual(_, value) => label != Some(value),
+            Self::In(_, values) => label.map_or(false, |label| values.contains(label)),
+            Self::NotIn(_, values) => label.map_or(true, |label| !values.contains(label)),
+            Self::Exists(_) => label.is_some(),
+            Self::DoesNotExist(_) => label.is_none(),
+        }
+    }
+
+    /// Validate the key and values of the expression.
+    pub fn validate(&self) -> Result<(), Error> {
+        validate_key(self.key())?;
+        match self {
+            Self::Equal(_, value) | Self::NotEqual(_, value) => validate_value(value),
+            Self::In(_, values) | Self::NotIn(_, values) => {
+                values.iter().try_for_each(|value| validate_value(value))
+            }
+            Self::Exists(_) | Self::DoesNotExist(_) => Ok(()),
+        }
+    }
+}
+
+impl ToSelector for Expression {
+    fn to_selector(&self) -> String {
+        match self {
+            Self::Equal(key, value) => format!("{}={}", key, value),
+            Self::NotEqual(key, value) => format!("{}!={}", key, value),
+            Self::In(key, values) => format!("{} in ({})", key, values.join(",")),
+            Self::NotIn(key, values) => format!("{} notin ({})", key, values.join(",")),
+            Self::Exists(key) => key.clone(),
+            Self::DoesNotExist(key) => format!("!{}", key),
+        }
+    }
+}
+
+/// A label selector, consisting of expressions which must all match.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Selector(pub Vec<Expression>);
+
+impl Selector {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Add an expression to the selector.
+    pub fn with(mut self, expression: Expression) -> Self {
+        self.0.push(expression);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Check if all expressions match the provided labels.
+    ///
+    /// An empty selector matches everything.
+    pub fn matches(&self, labels: &BTreeMap<String, String>) -> bool {
+        self.0.iter().all(|e| e.matches(labels))
+    }
+
+    /// Validate all expressions of the selector.
+    pub fn validate(&self) -> Result<(), Error> {
+        self.0.iter().try_for_each(|e| e.validate())
+    }
+}
+
+impl ToSelector for Selector {
+    /// All expressions, joined to an "and" expression.
+    fn to_selector(&self) -> String {
+        self.0
+            .iter()
+            .map(|e| e.to_selector())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl From<Vec<Expression>> for Selector {
+    fn from(expressions: Vec<Expression>) -> Self {
+        Self(expressions)
+    }
+}
+
+impl FromIterator<Expression> for Selector {
+    fn from_iter<T: IntoIterator<Item = Expression>>(iter: T) -> Self {
+        Self(iter.into_iter().collect())
+    }
+}
+
+impl<S1, S2> From<&BTreeMap<S1, S2>> for Selector
+where
+    S1: ToString,
+    S2: ToString,
+{
+    fn from(labels: &BTreeMap<S1, S2>) -> Self {
+        labels
+            .iter()
+            .map(|(k, v)| Expression::Equal(k.to_string(), v.to_string()))
+            .collect()
+    }
+}
+
+impl TryFrom<&LabelSelectorRequirement> for Expression {
+    type Error = Error;
+
+    fn try_from(requirement: &LabelSelectorRequirement) -> Result<Self, Self::Error> {
+        let key = requirement.key.clone();
+        let values = requirement.values.clone().unwrap_or_default();
+
+        match requirement.operator.as_str() {
+            "In" | "NotIn" if values.is_empty() => Err(Error::MissingValues {
+                key,
+                operator: requirement.operator.clone(),
+            }),
+            "Exists" | "DoesNotExist" if !values.is_empty() => Err(Error::UnexpectedValues {
+                key,
+                operator: requirement.operator.clone(),
+            }),
+            "In" => Ok(Self::In(key, values)),
+            "NotIn" => Ok(Self::NotIn(key, values)),
+            "Exists" => Ok(Self::Exists(key)),
+            "DoesNotExist" => Ok(Self::DoesNotExist(key)),
+            operator => Err(Error::InvalidOperator(operator.to_string())),
+        }
+    }
+}
+
+impl TryFrom<&LabelSelector> for Selector {
+    type Error = Error;
+
+    /// Convert a label selector, combining the `matchLabels` and the `matchExpressions`.
+    fn try_from(selector: &LabelSelector) -> Result<Self, Self::Error> {
+        let mut result = selector
+            .match_labels
+            .as_ref()
+            .map(Selector::from)
+            .unwrap_or_default();
+
+        for requirement in selector.match_expressions.iter().flatten() {
+            result.0.push(Expression::try_from(requirement)?);
+        }
+
+        result.validate()?;
+
+        Ok(result)
+    }
+}
+
+impl TryFrom<LabelSelector> for Selector {
+    type Error = Error;
+
+    fn try_from(selector: LabelSelector) -> Result<Self, Self::Error> {
+        Self::try_from(&selector)
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_expressions() {
+        let selector = Selector::new()
+            .with(Expression::Equal("a".into(), "1".into()))
+            .with(Expression::NotEqual("b".into(), "2".into()))
+            .with(Expression::In("c".into(), vec!["3".into(), "4".into()]))
+            .with(Expression::NotIn("d".into(), vec!["5".into()]))
+            .with(Expression::Exists("e".into()))
+            .with(Expression::DoesNotExist("f".into()));
+
+        assert_eq!(
+            "a=1,b!=2,c in (3,4),d notin (5),e,!f",
+            selector.to_selector()
+        );
+    }
+
+    #[test]
+    fn test_matches() {
+        let labels: BTreeMap<String, String> = [
+            ("app".to_string(), "foo".to_string()),
+            ("tier".to_string(), "frontend".to_string()),
+        ]
+        .into();
+        let matches = |s: &str| s.parse::<Selector>().unwrap().matches(&labels);
+
+        assert!(matches(""));
+        assert!(matches("app=foo"));
+        assert!(matches("app==foo,tier=frontend"));
+        assert!(!matches("app=bar"));
+        assert!(!matches("missing=foo"));
+
+        assert!(matches("app!=bar"));
+        assert!(!matches("app!=foo"));
+        assert!(matches("missing!=foo"));
+
+        assert!(matches("tier in (frontend,backend)"));
+        assert!(!matches("tier in (backend)"));
+        assert!(!matches("missing in (backend)"));
+
+        assert!(matches("tier notin (backend)"));
+        assert!(!matches("tier notin (frontend,backend)"));
+        assert!(matches("missing notin (backend)"));
+
+        assert!(matches("app"));
+        assert!(!matches("missing"));
+        assert!(matches("!missing"));
+        assert!(!matches("!app"));
+
+        assert!(!matches("app=foo,!tier"));
+    }
+
+    #[test]
+    fn test_label_selector() {
+        let selector = LabelSelector {
+            match_labels: Some([("app".to_string(), "foo".to_string())].into()),
+            match_expressions: Some(vec![
+                LabelSelectorRequirement {
+                    key: "tier".into(),
+                    operator: "In".into(),
+                    values: Some(vec!["frontend".into(), "backend".into()]),
+                },
+                LabelSelectorRequirement {
+                    key: "legacy".into(),
+                    operator: "DoesNotExist".into(),
+                    values: None,
+                },
+            ]),
+        };
+
+        assert_eq!(
+            "app=foo,tier in (frontend,backend),!legacy",
+            Selector::try_from(&selector).unwrap().to_selector()
+        );
+    }
+
+    #[test]
+    fn test_label_selector_invalid() {
+        let selector = |operator: &str, values: Option<Vec<String>>| LabelSelector {
+            match_labels: None,
+            match_expressions: Some(vec![LabelSelectorRequirement {
+                key: "foo".into(),
+                operator: operator.into(),
+                values,
+            }]),
+        };
+
+        assert_eq!(
+            Selector::try_from(selector("Foo", None)),
+            Err(Error::InvalidOperator("Foo".into()))
+        );
+        assert_eq!(
+            Selector::try_from(selector("In", None)),
+            Err(Error::MissingValues {
+                key: "foo".into(),
+                operator: "In".into()
+            })
+        );
+        assert_eq!(
+            Selector::try_from(selector("Exists", Some(vec!["bar".into()]))),
+            Err(Error::UnexpectedValues {
+                key: "foo".into(),
+                operator: "Exists".into()
+            })
+        );
+    }
+}
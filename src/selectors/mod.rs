@@ -0,0 +1,188 @@
+/*
+ * Copyright (c) 2020 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+mod field;
+mod label;
+mod parser;
+
+pub use field::*;
+pub use label::*;
+pub use parser::*;
+
+use kube::api::ListParams;
+use std::{
+    collections::BTreeMap,
+    fmt::{Display, Formatter},
+};
+
+/// An error when processing a selector.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// An unknown operator of a label selector requirement.
+    InvalidOperator(String),
+    /// The operator requires values, but none were provided.
+    MissingValues { key: String, operator: String },
+    /// The operator must not have values, but some were provided.
+    UnexpectedValues { key: String, operator: String },
+    /// The selector could not be parsed.
+    Syntax { position: usize, message: String },
+    /// A key is not a valid label key.
+    InvalidKey { key: String, reason: String },
+    /// A value is not a valid label value.
+    InvalidValue { value: String, reason: String },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidOperator(operator) => write!(f, "Invalid operator: '{}'", operator),
+            Self::MissingValues { key, operator } => write!(
+                f,
+                "Operator '{}' of key '{}' requires at least one value",
+                operator, key
+            ),
+            Self::UnexpectedValues { key, operator } => write!(
+                f,
+                "Operator '{}' of key '{}' must not have any values",
+                operator, key
+            ),
+            Self::Syntax { position, message } => {
+                write!(f, "Syntax error at position {}: {}", position, message)
+            }
+            Self::InvalidKey { key, reason } => write!(f, "Invalid key '{}': {}", key, reason),
+            Self::InvalidValue { value, reason } => {
+                write!(f, "Invalid value '{}': {}", value, reason)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+pub trait ToSelector {
+    /// Convert to a valid selector expression
+    fn to_selector(&self) -> String;
+}
+
+impl<S1, S2> ToSelector for BTreeMap<S1, S2>
+where
+    S1: ToString,
+    S2: AsRef<str>,
+{
+    /// For a map, we generate an "and" expression, consisting of all key/value pairs.
+    fn to_selector(&self) -> String {
+        self.iter()
+            .map(|(k, v)| k.to_string() + "=" + v.as_ref())
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+}
+
+/// Set selectors of [`ListParams`] from a [`ToSelector`].
+///
+/// An empty selector leaves the parameters unchanged, as it would select everything anyway.
+///
+/// ```
+/// use kube::api::ListParams;
+/// use operator_framework::selectors::{FieldSelector, Selector, WithSelectors};
+///
+/// let lp = ListParams::default()
+///     .with_labels(&"app=foo".parse::<Selector>().unwrap())
+///     .with_fields(&FieldSelector::new().equal("spec.nodeName", "node-1"));
+///
+/// assert_eq!(lp.label_selector.as_deref(), Some("app=foo"));
+/// assert_eq!(lp.field_selector.as_deref(), Some("spec.nodeName=node-1"));
+/// ```
+pub trait WithSelectors {
+    /// Set the label selector.
+    fn with_labels<S>(self, selector: &S) -> Self
+    where
+        S: ToSelector + ?Sized;
+
+    /// Set the field selector.
+    fn with_fields<S>(self, selector: &S) -> Self
+    where
+        S: ToSelector + ?Sized;
+}
+
+fn non_empty(selector: String) -> Option<String> {
+    match selector.is_empty() {
+        true => None,
+        false => Some(selector),
+    }
+}
+
+impl WithSelectors for ListParams {
+    fn with_labels<S>(mut self, selector: &S) -> Self
+    where
+        S: ToSelector + ?Sized,
+    {
+        if let Some(selector) = non_empty(selector.to_selector()) {
+            self.label_selector = Some(selector);
+        }
+        self
+    }
+
+    fn with_fields<S>(mut self, selector: &S) -> Self
+    where
+        S: ToSelector + ?Sized,
+    {
+        if let Some(selector) = non_empty(selector.to_selector()) {
+            self.field_selector = Some(selector);
+        }
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_empty() {
+        let labels = BTreeMap::<String, String>::new();
+        assert_eq!("", labels.to_selector());
+    }
+
+    #[test]
+    fn test_single() {
+        let mut labels = BTreeMap::new();
+        labels.insert("foo", "bar");
+        assert_eq!("foo=bar", labels.to_selector());
+    }
+
+    #[test]
+    fn test_multiple() {
+        let mut labels = BTreeMap::new();
+        labels.insert("foo", "bar");
+        labels.insert("bar", "baz");
+        let sel = labels.to_selector();
+
+        // the map doesn't provide an order, so we need to check for both variants
+        assert!(sel == "foo=bar,bar=baz" || sel == "bar=baz,foo=bar");
+    }
+
+    #[test]
+    fn test_list_params() {
+        let mut labels = BTreeMap::new();
+        labels.insert("app", "foo");
+
+        let lp = ListParams::default()
+            .with_labels(&labels)
+            .with_fields(&FieldSelector::new());
+
+        assert_eq!(lp.label_selector.as_deref(), Some("app=foo"));
+        assert_eq!(lp.field_selector, None);
+    }
+}
@@ -0,0 +1,479 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+use super::{Error, Expression, Selector};
+use std::str::FromStr;
+
+const MAX_NAME_LENGTH: usize = 63;
+const MAX_PREFIX_LENGTH: usize = 253;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Identifier(String),
+    Not,
+    Equal,
+    DoubleEqual,
+    NotEqual,
+    Comma,
+    Open,
+    Close,
+}
+
+impl Token {
+    fn describe(&self) -> String {
+        match self {
+            Self::Identifier(value) => format!("'{}'", value),
+            Self::Not => "'!'".into(),
+            Self::Equal => "'='".into(),
+            Self::DoubleEqual => "'=='".into(),
+            Self::NotEqual => "'!='".into(),
+            Self::Comma => "','".into(),
+            Self::Open => "'('".into(),
+            Self::Close => "')'".into(),
+        }
+    }
+}
+
+fn is_special(c: char) -> bool {
+    c.is_whitespace() || matches!(c, '!' | '=' | ',' | '(' | ')')
+}
+
+/// Split the input into tokens, along with their (character) position.
+fn lex(input: &str) -> Vec<(usize, Token)> {
+    let mut tokens = Vec::new();
+    let mut chars = input.chars().enumerate().peekable();
+
+    while let Some((pos, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '!' if chars.next_if(|(_, c)| *c == '=').is_some() => Token::NotEqual,
+            '!' => Token::Not,
+            '=' if chars.next_if(|(_, c)| *c == '=').is_some() => Token::DoubleEqual,
+            '=' => Token::Equal,
+            ',' => Token::Comma,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            c => {
+                let mut value = c.to_string();
+                while let Some((_, c)) = chars.next_if(|(_, c)| !is_special(*c)) {
+                    value.push(c);
+                }
+                Token::Identifier(value)
+            }
+        };
+        tokens.push((pos, token));
+    }
+
+    tokens
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    index: usize,
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index).map(|(_, t)| t)
+    }
+
+    fn position(&self) -> usize {
+        self.tokens
+            .get(self.index)
+            .map(|(pos, _)| *pos)
+            .unwrap_or(self.end)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).map(|(_, t)| t.clone());
+        self.index += 1;
+        token
+    }
+
+    fn error<S: Into<String>>(&self, message: S) -> Error {
+        Error::Syntax {
+            position: self.position(),
+            message: message.into(),
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> Error {
+        match self.peek() {
+            Some(token) => self.error(format!("expected {}, found {}", expected, token.describe())),
+            None => self.error(format!("expected {}, found end of input", expected)),
+        }
+    }
+
+    fn parse(mut self) -> Result<Selector, Error> {
+        let mut expressions = Vec::new();
+
+        if self.peek().is_none() {
+            return Ok(Selector(expressions));
+        }
+
+        loop {
+            expressions.push(self.requirement()?);
+            match self.next() {
+                None => break,
+                Some(Token::Comma) => continue,
+                Some(_) => {
+                    self.index -= 1;
+                    return Err(self.unexpected("','"));
+                }
+            }
+        }
+
+        Ok(Selector(expressions))
+    }
+
+    fn key(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            Some(Token::Identifier(key)) => {
+                let key = key.clone();
+                validate_key(&key)?;
+                self.index += 1;
+                Ok(key)
+            }
+            _ => Err(self.unexpected("a key")),
+        }
+    }
+
+    fn requirement(&mut self) -> Result<Expression, Error> {
+        if self.peek() == Some(&Token::Not) {
+            self.index += 1;
+            return Ok(Expression::DoesNotExist(self.key()?));
+        }
+
+        let key = self.key()?;
+
+        match self.peek() {
+            None | Some(Token::Comma) => Ok(Expression::Exists(key)),
+            Some(Token::Equal | Token::DoubleEqual) => {
+                self.index += 1;
+                Ok(Expression::Equal(key, self.exact_value()?))
+            }
+            Some(Token::NotEqual) => {
+                self.index += 1;
+                Ok(Expression::NotEqual(key, self.exact_value()?))
+            }
+            Some(Token::Identifier(op)) if op == "in" => {
+                self.index += 1;
+                let values = self.values(&key, "in")?;
+                Ok(Expression::In(key, values))
+            }
+            Some(Token::Identifier(op)) if op == "notin" => {
+                self.index += 1;
+                let values = self.values(&key, "notin")?;
+                Ok(Expression::NotIn(key, values))
+            }
+            _ => Err(self.unexpected("an operator ('=', '==', '!=', 'in', 'notin'), ',' or end")),
+        }
+    }
+
+    /// A single value, which may be empty.
+    fn exact_value(&mut self) -> Result<String, Error> {
+        match self.peek() {
+            None | Some(Token::Comma) => Ok(String::new()),
+            Some(Token::Identifier(value)) => {
+                let value = value.clone();
+                validate_value(&value)?;
+                self.index += 1;
+                Ok(value)
+            }
+            _ => Err(self.unexpected("a value")),
+        }
+    }
+
+    /// A non-empty set of values, in parentheses.
+    fn values(&mut self, key: &str, operator: &str) -> Result<Vec<String>, Error> {
+        if self.peek() != Some(&Token::Open) {
+            return Err(self.unexpected("'('"));
+        }
+        self.index += 1;
+
+        if self.peek() == Some(&Token::Close) {
+            return Err(Error::MissingValues {
+                key: key.to_string(),
+                operator: operator.to_string(),
+            });
+        }
+
+        let mut values = Vec::new();
+        loop {
+            match self.peek() {
+                Some(Token::Identifier(value)) => {
+                    let value = value.clone();
+                    validate_value(&value)?;
+                    self.index += 1;
+                    values.push(value);
+                }
+                // an omitted value is an empty one
+                Some(Token::Comma | Token::Close) => values.push(String::new()),
+                _ => return Err(self.unexpected("a value, ',' or ')'")),
+            }
+
+            match self.next() {
+                Some(Token::Comma) => continue,
+                Some(Token::Close) => break,
+                _ => {
+                    self.index -= 1;
+                    return Err(self.unexpected("',' or ')'"));
+                }
+            }
+        }
+
+        Ok(values)
+    }
+}
+
+impl FromStr for Selector {
+    type Err = Error;
+
+    /// Parse a label selector, using the same syntax as the Kubernetes API server.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Parser {
+            tokens: lex(s),
+            index: 0,
+            end: s.chars().count(),
+        }
+        .parse()
+    }
+}
+
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')
+}
+
+/// Check the "name" format, shared by keys and values.
+fn check_name(name: &str) -> Result<(), String> {
+    if name.len() > MAX_NAME_LENGTH {
+        return Err(format!(
+            "must be no more than {} characters",
+            MAX_NAME_LENGTH
+        ));
+    }
+    if let Some(c) = name.chars().find(|c| !is_name_char(*c)) {
+        return Err(format!(
+            "must consist of alphanumeric characters, '-', '_' or '.', found '{}'",
+            c
+        ));
+    }
+    if !name.starts_with(|c: char| c.is_ascii_alphanumeric())
+        || !name.ends_with(|c: char| c.is_ascii_alphanumeric())
+    {
+        return Err("must start and end with an alphanumeric character".into());
+    }
+    Ok(())
+}
+
+/// Check the prefix of a key, which must be a DNS subdomain.
+fn check_prefix(prefix: &str) -> Result<(), String> {
+    if prefix.len() > MAX_PREFIX_LENGTH {
+        return Err(format!(
+            "prefix must be no more than {} characters",
+            MAX_PREFIX_LENGTH
+        ));
+    }
+    for label in prefix.split('.') {
+        let valid = label
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+            && !label.starts_with('-')
+            && !label.ends_with('-')
+            && !label.is_empty();
+        if !valid {
+            return Err(format!(
+                "prefix must be a DNS subdomain, consisting of lower case alphanumeric characters, '-' or '.', found '{}'",
+                prefix
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Validate a label key, which is a name with an optional DNS subdomain prefix.
+pub fn validate_key(key: &str) -> Result<(), Error> {
+    let invalid = |reason: String| Error::InvalidKey {
+        key: key.to_string(),
+        reason,
+    };
+
+    let name = match key.split_once('/') {
+        Some((prefix, name)) => {
+            if prefix.is_empty() {
+                return Err(invalid("prefix must not be empty".into()));
+            }
+            check_prefix(prefix).map_err(invalid)?;
+            name
+        }
+        None => key,
+    };
+
+    if name.is_empty() {
+        return Err(invalid("name must not be empty".into()));
+    }
+
+    check_name(name).map_err(|reason| invalid(format!("name {}", reason)))
+}
+
+/// Validate a label value, which may be empty.
+pub fn validate_value(value: &str) -> Result<(), Error> {
+    if value.is_empty() {
+        return Ok(());
+    }
+
+    check_name(value).map_err(|reason| Error::InvalidValue {
+        value: value.to_string(),
+        reason: format!("value {}", reason),
+    })
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use crate::selectors::ToSelector;
+
+    fn parse(s: &str) -> Result<Selector, Error> {
+        s.parse()
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(""), Ok(Selector::new()));
+        assert_eq!(parse("  "), Ok(Selector::new()));
+        assert_eq!(
+            parse("a=1, b==2,c!=3,d in (4, 5),e notin (6),f,!g,h=").unwrap(),
+            Selector(vec![
+                Expression::Equal("a".into(), "1".into()),
+                Expression::Equal("b".into(), "2".into()),
+                Expression::NotEqual("c".into(), "3".into()),
+                Expression::In("d".into(), vec!["4".into(), "5".into()]),
+                Expression::NotIn("e".into(), vec!["6".into()]),
+                Expression::Exists("f".into()),
+                Expression::DoesNotExist("g".into()),
+                Expression::Equal("h".into(), "".into()),
+            ])
+        );
+        assert_eq!(
+            parse("example.com/app=foo").unwrap(),
+            Selector(vec![Expression::Equal(
+                "example.com/app".into(),
+                "foo".into()
+            )])
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let selector = "a=1,b!=2,c in (3,4),d notin (5),e,!f";
+        assert_eq!(selector, parse(selector).unwrap().to_selector());
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        assert_eq!(
+            parse("a=1,"),
+            Err(Error::Syntax {
+                position: 4,
+                message: "expected a key, found end of input".into()
+            })
+        );
+        assert_eq!(
+            parse("a in 1"),
+            Err(Error::Syntax {
+                position: 5,
+                message: "expected '(', found '1'".into()
+            })
+        );
+        assert_eq!(
+            parse("a in (1"),
+            Err(Error::Syntax {
+                position: 7,
+                message: "expected ',' or ')', found end of input".into()
+            })
+        );
+        assert!(matches!(
+            parse("a b"),
+            Err(Error::Syntax { position: 2, .. })
+        ));
+        assert!(matches!(
+            parse("a=(1)"),
+            Err(Error::Syntax { position: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn test_missing_values() {
+        assert_eq!(
+            parse("a in ()"),
+            Err(Error::MissingValues {
+                key: "a".into(),
+                operator: "in".into()
+            })
+        );
+        assert_eq!(
+            parse("a notin ( )"),
+            Err(Error::MissingValues {
+                key: "a".into(),
+                operator: "notin".into()
+            })
+        );
+        // an omitted value is still an empty value
+        assert_eq!(
+            parse("a in (,b)").unwrap(),
+            Selector(vec![Expression::In(
+                "a".into(),
+                vec!["".into(), "b".into()]
+            )])
+        );
+    }
+
+    #[test]
+    fn test_invalid_keys() {
+        assert!(validate_key("foo").is_ok());
+        assert!(validate_key("foo.bar_baz-1").is_ok());
+        assert!(validate_key("example.com/foo").is_ok());
+
+        assert!(matches!(parse("-foo"), Err(Error::InvalidKey { .. })));
+        assert!(matches!(parse("/foo"), Err(Error::InvalidKey { .. })));
+        assert!(matches!(parse("foo/"), Err(Error::InvalidKey { .. })));
+        assert!(matches!(
+            parse("Example.com/foo"),
+            Err(Error::InvalidKey { .. })
+        ));
+        assert!(matches!(parse("a/b/c"), Err(Error::InvalidKey { .. })));
+        assert!(validate_key(&"a".repeat(64)).is_err());
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        assert!(validate_value("").is_ok());
+        assert!(validate_value("Foo.bar_baz-1").is_ok());
+
+        assert_eq!(
+            parse("a=b/c"),
+            Err(Error::InvalidValue {
+                value: "b/c".into(),
+                reason: "value must consist of alphanumeric characters, '-', '_' or '.', found '/'"
+                    .into()
+            })
+        );
+        assert!(matches!(
+            parse("a in (-b)"),
+            Err(Error::InvalidValue { .. })
+        ));
+        assert!(validate_value(&"a".repeat(64)).is_err());
+    }
+}
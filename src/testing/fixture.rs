@@ -0,0 +1,92 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+use crate::install::meta::{Meta, OwnedBy};
+use k8s_openapi::{api::core::v1::ConfigMap, apimachinery::pkg::apis::meta::v1::ObjectMeta};
+
+/// Start building a `ConfigMap` in the `default` namespace, to be used as test fixture.
+///
+/// ```ignore
+/// server.insert(config_map("foo").label("app", "foo").finalizer("example.com/test").build());
+/// ```
+pub fn config_map<S: Into<String>>(name: S) -> ConfigMapBuilder {
+    ConfigMapBuilder::new(name)
+}
+
+/// A builder for `ConfigMap` test fixtures.
+#[derive(Clone, Debug)]
+pub struct ConfigMapBuilder(ConfigMap);
+
+impl ConfigMapBuilder {
+    pub fn new<S: Into<String>>(name: S) -> Self {
+        Self(ConfigMap {
+            metadata: ObjectMeta {
+                name: Some(name.into()),
+                namespace: Some("default".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    pub fn namespace<S: Into<String>>(mut self, namespace: S) -> Self {
+        self.0.metadata.namespace = Some(namespace.into());
+        self
+    }
+
+    pub fn label<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.0
+            .metadata
+            .labels
+            .get_or_insert_with(Default::default)
+            .insert(key.into(), value.into());
+        self
+    }
+
+    pub fn finalizer<S: Into<String>>(mut self, finalizer: S) -> Self {
+        self.0
+            .metadata
+            .finalizers
+            .get_or_insert_with(Default::default)
+            .push(finalizer.into());
+        self
+    }
+
+    pub fn data<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.0
+            .data
+            .get_or_insert_with(Default::default)
+            .insert(key.into(), value.into());
+        self
+    }
+
+    /// Add the owner as controller.
+    ///
+    /// Panics if the owner can't be the controller of the `ConfigMap`.
+    pub fn owned_by<R: Meta>(mut self, owner: &R) -> Self {
+        self.0
+            .owned_by_controller(owner)
+            .expect("owner must be valid");
+        self
+    }
+
+    pub fn build(self) -> ConfigMap {
+        self.0
+    }
+}
+
+impl From<ConfigMapBuilder> for ConfigMap {
+    fn from(builder: ConfigMapBuilder) -> Self {
+        builder.build()
+    }
+}
@@ -0,0 +1,425 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+//! Testing helpers, available with the `testing` feature.
+//!
+//! The [`FakeServer`] is an in-memory Kubernetes API server, which allows running code using a
+//! [`kube::Client`] without a cluster:
+//!
+//! ```ignore
+//! let server = FakeServer::new();
+//! server.insert(ConfigMap { .. });
+//!
+//! let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+//! // run the code under test
+//!
+//! assert_eq!(server.get::<ConfigMap>(Some("default"), "foo").unwrap().data, ..);
+//! ```
+//!
+//! The server supports getting, listing (including label and field selectors), creating,
+//! replacing, patching, deleting and watching resources, as well as the status subresource. It tracks the
+//! `resourceVersion`, `uid` and `generation` of resources, and reports conflicts and missing
+//! resources like the real API server.
+//!
+//! Note: all resources are handled as if they had a status subresource, so updating the main
+//! resource keeps the status unchanged. Server-side apply patches are handled like merge patches.
+//! There is no garbage collection, so propagation policies are ignored. Only the latest 1000
+//! changes are kept for watches, watches starting at an older resource version fail with
+//! `410 Gone`.
+//!
+//! Test fixtures can be created using builders, like [`config_map`].
+
+mod fixture;
+mod server;
+
+pub use self::fixture::*;
+
+use self::server::{ApiPath, Store};
+use http::{Request, Response};
+use hyper::Body;
+use kube::{Client, Resource};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    convert::Infallible,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+/// An in-memory Kubernetes API server.
+///
+/// The server can be cloned, clones share the same state.
+#[derive(Clone, Default)]
+pub struct FakeServer {
+    store: Arc<Mutex<Store>>,
+}
+
+impl FakeServer {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Create a client using this server, with `default` as the default namespace.
+    pub fn client(&self) -> Client {
+        Client::new(self.clone(), "default")
+    }
+
+    /// Insert a resource, bypassing the API.
+    ///
+    /// The resource gets stored like it was created, including a new `resourceVersion` and `uid`.
+    /// An existing resource with the same name gets replaced. Returns the stored resource.
+    pub fn insert<K>(&self, resource: K) -> K
+    where
+        K: Resource<DynamicType = ()> + Serialize + DeserializeOwned,
+    {
+        let path = ApiPath::for_resource(&resource);
+        let value = serde_json::to_value(&resource).expect("resource must serialize");
+        let value = self.store.lock().unwrap().insert(&path, value);
+        serde_json::from_value(value).expect("resource must deserialize")
+    }
+
+    /// Get a resource, bypassing the API.
+    pub fn get<K>(&self, namespace: Option<&str>, name: &str) -> Option<K>
+    where
+        K: Resource<DynamicType = ()> + DeserializeOwned,
+    {
+        let path = ApiPath::for_type::<K>(namespace).with_name(name);
+        self.store
+            .lock()
+            .unwrap()
+            .get(&path)
+            .map(|value| serde_json::from_value(value).expect("resource must deserialize"))
+    }
+
+    /// Reject requests deleting a collection of this type, like for resources which don't
+    /// support the `deletecollection` verb.
+    pub fn disable_delete_collection<K>(&self)
+    where
+        K: Resource<DynamicType = ()>,
+    {
+        self.store
+            .lock()
+            .unwrap()
+            .disable_delete_collection(&ApiPath::for_type::<K>(None));
+    }
+
+    /// Respond to requests deleting a collection of this type with a `Status`, instead of the
+    /// list of deleted resources, like some API servers do.
+    pub fn status_delete_collection<K>(&self)
+    where
+        K: Resource<DynamicType = ()>,
+    {
+        self.store
+            .lock()
+            .unwrap()
+            .status_delete_collection(&ApiPath::for_type::<K>(None));
+    }
+
+    /// List all resources of a type, bypassing the API.
+    ///
+    /// If the namespace is `None`, resources of all namespaces are returned.
+    pub fn list<K>(&self, namespace: Option<&str>) -> Vec<K>
+    where
+        K: Resource<DynamicType = ()> + DeserializeOwned,
+    {
+        let path = ApiPath::for_type::<K>(namespace);
+        self.store
+            .lock()
+            .unwrap()
+            .list(&path)
+            .into_iter()
+            .map(|value| serde_json::from_value(value).expect("resource must deserialize"))
+            .collect()
+    }
+}
+
+impl tower::Service<Request<Body>> for FakeServer {
+    type Response = Response<Body>;
+    type Error = Infallible;
+    type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        let store = self.store.clone();
+        Box::pin(async move {
+            let (parts, body) = request.into_parts();
+            let body = hyper::body::to_bytes(body).await.unwrap_or_default();
+            let response = store.lock().unwrap().handle(&parts, &body);
+            Ok(response)
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+    use futures::{StreamExt, TryStreamExt};
+    use k8s_openapi::api::core::v1::ConfigMap;
+    use kube::{
+        api::{
+            DeleteParams, ListParams, Patch, PatchParams, PostParams, Preconditions, WatchEvent,
+        },
+        Api, ResourceExt,
+    };
+    use serde_json::json;
+
+    fn code(err: kube::Error) -> u16 {
+        match err {
+            kube::Error::Api(err) => err.code,
+            err => panic!("Unexpected error: {}", err),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_crud() {
+        let server = FakeServer::new();
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+
+        assert_eq!(code(api.get("foo").await.unwrap_err()), 404);
+
+        let created = api
+            .create(&PostParams::default(), &config_map("foo").build())
+            .await
+            .unwrap();
+        assert!(created.metadata.uid.is_some());
+        assert!(created.metadata.resource_version.is_some());
+        assert!(created.metadata.creation_timestamp.is_some());
+
+        assert_eq!(
+            code(
+                api.create(&PostParams::default(), &config_map("foo").build())
+                    .await
+                    .unwrap_err()
+            ),
+            409
+        );
+
+        // replace with the current resource version
+
+        let mut update = created.clone();
+        update.data = Some([("key".to_string(), "new".to_string())].into());
+        let updated = api
+            .replace("foo", &PostParams::default(), &update)
+            .await
+            .unwrap();
+        assert_ne!(
+            updated.metadata.resource_version,
+            created.metadata.resource_version
+        );
+        assert_eq!(updated.metadata.uid, created.metadata.uid);
+
+        // replace with an outdated resource version
+
+        assert_eq!(
+            code(
+                api.replace("foo", &PostParams::default(), &update)
+                    .await
+                    .unwrap_err()
+            ),
+            409
+        );
+
+        // merge patch
+
+        let patched = api
+            .patch(
+                "foo",
+                &PatchParams::default(),
+                &Patch::Merge(json!({"data": {"other": "value"}})),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            patched.data,
+            Some(
+                [
+                    ("key".to_string(), "new".to_string()),
+                    ("other".to_string(), "value".to_string())
+                ]
+                .into()
+            )
+        );
+
+        // delete, with failing preconditions
+
+        let dp = DeleteParams {
+            preconditions: Some(Preconditions {
+                resource_version: created.metadata.resource_version.clone(),
+                uid: None,
+            }),
+            ..Default::default()
+        };
+        assert_eq!(code(api.delete("foo", &dp).await.unwrap_err()), 409);
+
+        assert!(api
+            .delete("foo", &DeleteParams::default())
+            .await
+            .unwrap()
+            .is_right());
+        assert!(server.get::<ConfigMap>(Some("default"), "foo").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_list() {
+        let server = FakeServer::new();
+        server.insert(config_map("foo").label("app", "foo").build());
+        server.insert(config_map("bar").label("app", "bar").build());
+        server.insert(config_map("baz").namespace("other").build());
+
+        let names = |list: kube::core::ObjectList<ConfigMap>| {
+            list.items
+                .into_iter()
+                .map(|cm| cm.metadata.name.unwrap_or_default())
+                .collect::<Vec<_>>()
+        };
+
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+        assert_eq!(
+            names(api.list(&ListParams::default()).await.unwrap()),
+            vec!["bar", "foo"]
+        );
+        assert_eq!(
+            names(
+                api.list(&ListParams::default().labels("app=foo"))
+                    .await
+                    .unwrap()
+            ),
+            vec!["foo"]
+        );
+        assert_eq!(
+            names(
+                api.list(&ListParams::default().fields("metadata.name!=foo"))
+                    .await
+                    .unwrap()
+            ),
+            vec!["bar"]
+        );
+
+        let api = Api::<ConfigMap>::all(server.client());
+        assert_eq!(
+            names(api.list(&ListParams::default()).await.unwrap()),
+            vec!["bar", "foo", "baz"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_finalizers() {
+        let server = FakeServer::new();
+        server.insert(config_map("foo").finalizer("example.com/test").build());
+
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+
+        let deleting = api
+            .delete("foo", &DeleteParams::default())
+            .await
+            .unwrap()
+            .left()
+            .unwrap();
+        assert!(deleting.metadata.deletion_timestamp.is_some());
+
+        api.patch(
+            "foo",
+            &PatchParams::default(),
+            &Patch::Merge(json!({"metadata": {"finalizers": null}})),
+        )
+        .await
+        .unwrap();
+
+        assert!(server.get::<ConfigMap>(Some("default"), "foo").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_watch() {
+        let server = FakeServer::new();
+        let foo = server.insert(config_map("foo").build());
+        server.insert(config_map("bar").build());
+
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+        let lp = ListParams::default().fields("metadata.name=foo");
+
+        let event = |event: WatchEvent<ConfigMap>| match event {
+            WatchEvent::Added(cm) => ("ADDED", cm.name_any()),
+            WatchEvent::Modified(cm) => ("MODIFIED", cm.name_any()),
+            WatchEvent::Deleted(cm) => ("DELETED", cm.name_any()),
+            other => panic!("Unexpected event: {:?}", other),
+        };
+
+        // start with the current state
+
+        let events = api.watch(&lp, "0").await.unwrap().boxed();
+        let events: Vec<_> = events.take(1).map_ok(event).try_collect().await.unwrap();
+        assert_eq!(events, vec![("ADDED", "foo".to_string())]);
+
+        // start with a resource version, and follow changes
+
+        let mut events = api
+            .watch(&lp, &foo.resource_version().unwrap())
+            .await
+            .unwrap()
+            .boxed();
+
+        api.patch(
+            "bar",
+            &PatchParams::default(),
+            &Patch::Merge(json!({"data": {"other": "value"}})),
+        )
+        .await
+        .unwrap();
+        api.patch(
+            "foo",
+            &PatchParams::default(),
+            &Patch::Merge(json!({"data": {"other": "value"}})),
+        )
+        .await
+        .unwrap();
+        api.delete("foo", &DeleteParams::default()).await.unwrap();
+
+        assert_eq!(
+            event(events.try_next().await.unwrap().unwrap()),
+            ("MODIFIED", "foo".to_string())
+        );
+        assert_eq!(
+            event(events.try_next().await.unwrap().unwrap()),
+            ("DELETED", "foo".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_watch_expired() {
+        let server = FakeServer::new();
+        let foo = server.insert(config_map("foo").build());
+        // more changes than the server keeps for watches
+        for i in 0..=1000 {
+            server.insert(config_map("foo").data("key", i.to_string()).build());
+        }
+
+        let api = Api::<ConfigMap>::namespaced(server.client(), "default");
+        let events: Vec<_> = api
+            .watch(&ListParams::default(), &foo.resource_version().unwrap())
+            .await
+            .unwrap()
+            .try_collect()
+            .await
+            .unwrap();
+
+        match events.as_slice() {
+            [WatchEvent::Error(err)] => assert_eq!(err.code, 410),
+            other => panic!("Unexpected events: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,1129 @@
+/*
+ * Copyright (c) 2022 Jens Reimann and others.
+ *
+ * See the NOTICE file(s) distributed with this work for additional
+ * information regarding copyright ownership.
+ *
+ * This program and the accompanying materials are made available under the
+ * terms of the Eclipse Public License 2.0 which is available at
+ * http://www.eclipse.org/legal/epl-2.0
+ *
+ * SPDX-License-Identifier: EPL-2.0
+ */
+
+use crate::selectors::Selector;
+
+use chrono::Utc;
+use futures::{
+    channel::mpsc::{self, UnboundedReceiver, UnboundedSender},
+    StreamExt,
+};
+use http::{header::CONTENT_TYPE, request::Parts, Method, Response, StatusCode};
+use hyper::{body::Bytes, Body};
+use kube::Resource;
+use rand::{distributions::Alphanumeric, Rng};
+use serde_json::{json, Value};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    convert::Infallible,
+};
+
+/// The location of a resource, or a collection of resources.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ApiPath {
+    /// The group and version, e.g. `api/v1` or `apis/apps/v1`.
+    pub api: String,
+    /// The plural name of the resource type.
+    pub plural: String,
+    pub namespace: Option<String>,
+    pub name: Option<String>,
+    pub subresource: Option<String>,
+}
+
+impl ApiPath {
+    pub fn for_type<K>(namespace: Option<&str>) -> Self
+    where
+        K: Resource<DynamicType = ()>,
+    {
+        Self::parse(&K::url_path(&(), namespace)).expect("resource must have a valid URL path")
+    }
+
+    pub fn for_resource<K>(resource: &K) -> Self
+    where
+        K: Resource<DynamicType = ()>,
+    {
+        let name = resource.meta().name.clone().unwrap_or_default();
+        Self::for_type::<K>(resource.meta().namespace.as_deref()).with_name(name)
+    }
+
+    pub fn with_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Parse a URL path, like `/apis/apps/v1/namespaces/default/deployments/foo/status`.
+    pub fn parse(path: &str) -> Option<Self> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+
+        let (api, rest) = match segments.as_slice() {
+            ["api", version, rest @ ..] => (format!("api/{}", version), rest),
+            ["apis", group, version, rest @ ..] => (format!("apis/{}/{}", group, version), rest),
+            _ => return None,
+        };
+
+        // a namespace is only a namespace if it is followed by a resource type, otherwise it is
+        // the resource type "namespaces" itself
+        let (namespace, rest) = match rest {
+            // subresources of the core "namespaces" resource
+            ["namespaces", _, "status" | "finalize"] if api == "api/v1" => (None, rest),
+            ["namespaces", namespace, rest @ ..] if !rest.is_empty() => {
+                (Some(namespace.to_string()), rest)
+            }
+            rest => (None, rest),
+        };
+
+        let (plural, name, subresource) = match rest {
+            [plural] => (plural, None, None),
+            [plural, name] => (plural, Some(name.to_string()), None),
+            [plural, name, sub] => (plural, Some(name.to_string()), Some(sub.to_string())),
+            _ => return None,
+        };
+
+        Some(Self {
+            api,
+            plural: plural.to_string(),
+            namespace,
+            name,
+            subresource,
+        })
+    }
+
+    fn from_key(key: &Key) -> Self {
+        Self {
+            api: key.0.clone(),
+            plural: key.1.clone(),
+            namespace: Some(key.2.clone()).filter(|ns| !ns.is_empty()),
+            name: Some(key.3.clone()),
+            subresource: None,
+        }
+    }
+
+    fn key(&self) -> Key {
+        (
+            self.api.clone(),
+            self.plural.clone(),
+            self.namespace.clone().unwrap_or_default(),
+            self.name.clone().unwrap_or_default(),
+        )
+    }
+
+    /// Check if a key belongs to the collection of this path.
+    fn contains(&self, key: &Key) -> bool {
+        key.0 == self.api
+            && key.1 == self.plural
+            && self.namespace.as_ref().map_or(true, |ns| &key.2 == ns)
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "{} \"{}\"",
+            self.plural,
+            self.name.as_deref().unwrap_or_default()
+        )
+    }
+}
+
+/// The key of a stored resource: group/version, plural, namespace, name.
+type Key = (String, String, String, String);
+
+/// A failed request, reported as a `Status`.
+#[derive(Clone, Debug)]
+struct Failure {
+    code: StatusCode,
+    reason: &'static str,
+    message: String,
+}
+
+impl Failure {
+    fn new<S: Into<String>>(code: StatusCode, reason: &'static str, message: S) -> Self {
+        Self {
+            code,
+            reason,
+            message: message.into(),
+        }
+    }
+
+    fn bad_request<S: Into<String>>(message: S) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "BadRequest", message)
+    }
+
+    fn not_found(path: &ApiPath) -> Self {
+        Self::new(
+            StatusCode::NOT_FOUND,
+            "NotFound",
+            format!("{} not found", path.describe()),
+        )
+    }
+
+    fn conflict<S: Into<String>>(path: &ApiPath, message: S) -> Self {
+        Self::new(
+            StatusCode::CONFLICT,
+            "Conflict",
+            format!(
+                "Operation cannot be fulfilled on {}: {}",
+                path.describe(),
+                message.into()
+            ),
+        )
+    }
+
+    fn with_message<S: Into<String>>(mut self, message: S) -> Self {
+        self.message = message.into();
+        self
+    }
+
+    fn into_response(self) -> Response<Body> {
+        respond(
+            self.code,
+            &json!({
+                "kind": "Status",
+                "apiVersion": "v1",
+                "metadata": {},
+                "status": "Failure",
+                "message": self.message,
+                "reason": self.reason,
+                "code": self.code.as_u16(),
+            }),
+        )
+    }
+}
+
+type Result<T> = std::result::Result<T, Failure>;
+
+fn respond(code: StatusCode, value: &Value) -> Response<Body> {
+    Response::builder()
+        .status(code)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(value.to_string()))
+        .unwrap()
+}
+
+/// The maximum number of changes kept for replaying them to watches.
+const MAX_EVENTS: usize = 1000;
+
+/// The state of the fake server.
+#[derive(Debug, Default)]
+pub(crate) struct Store {
+    resources: BTreeMap<Key, Value>,
+    resource_version: u64,
+    /// The latest changes, used to replay events to watches starting at an older resource version.
+    events: VecDeque<Event>,
+    /// The resource version of the latest change which was dropped from the events.
+    compacted: u64,
+    watchers: Vec<Watcher>,
+    /// Resource types (group/version, plural) which don't support deleting collections.
+    no_delete_collection: HashSet<(String, String)>,
+    /// Resource types (group/version, plural) which respond to deleting collections with a status.
+    status_delete_collection: HashSet<(String, String)>,
+}
+
+/// A change of a resource.
+#[derive(Debug)]
+struct Event {
+    resource_version: u64,
+    r#type: &'static str,
+    key: Key,
+    object: Value,
+}
+
+/// An active watch.
+#[derive(Debug)]
+struct Watcher {
+    path: ApiPath,
+    filter: Filter,
+    sender: UnboundedSender<Bytes>,
+}
+
+impl Watcher {
+    fn matches(&self, key: &Key, object: &Value) -> bool {
+        self.path.contains(key) && self.filter.matches(object)
+    }
+
+    /// Send an event, returns `false` if the watch is closed.
+    fn send(&self, r#type: &str, object: &Value) -> bool {
+        let event = json!({"type": r#type, "object": object}).to_string() + "\n";
+        self.sender.unbounded_send(Bytes::from(event)).is_ok()
+    }
+}
+
+/// The label and field selectors of a request.
+#[derive(Debug, Default)]
+struct Filter {
+    labels: Selector,
+    /// The field selector, as a list of `(field, equal, value)` requirements.
+    fields: Vec<(String, bool, String)>,
+}
+
+impl Filter {
+    fn from_query(query: &HashMap<String, String>) -> Result<Self> {
+        let labels = query
+            .get("labelSelector")
+            .map(|s| s.parse())
+            .transpose()
+            .map_err(|err| Failure::bad_request(format!("invalid label selector: {}", err)))?
+            .unwrap_or_default();
+        let fields = query
+            .get("fieldSelector")
+            .map(|s| parse_field_selector(s))
+            .transpose()?
+            .unwrap_or_default();
+
+        Ok(Self { labels, fields })
+    }
+
+    fn matches(&self, object: &Value) -> bool {
+        self.labels.matches(&labels(object))
+            && self
+                .fields
+                .iter()
+                .all(|(field, equal, value)| (field_value(object, field) == *value) == *equal)
+    }
+}
+
+impl Store {
+    /// Insert (or replace) a resource, without any checks.
+    pub fn insert(&mut self, path: &ApiPath, mut value: Value) -> Value {
+        let meta = metadata(&mut value);
+        meta.entry("uid").or_insert_with(|| uid().into());
+        meta.entry("creationTimestamp")
+            .or_insert_with(|| now().into());
+
+        self.put(path, value)
+    }
+
+    pub fn disable_delete_collection(&mut self, path: &ApiPath) {
+        self.no_delete_collection
+            .insert((path.api.clone(), path.plural.clone()));
+    }
+
+    pub fn status_delete_collection(&mut self, path: &ApiPath) {
+        self.status_delete_collection
+            .insert((path.api.clone(), path.plural.clone()));
+    }
+
+    pub fn get(&self, path: &ApiPath) -> Option<Value> {
+        self.resources.get(&path.key()).cloned()
+    }
+
+    pub fn list(&self, path: &ApiPath) -> Vec<Value> {
+        self.resources
+            .iter()
+            .filter(|(key, _)| path.contains(key))
+            .map(|(_, value)| value.clone())
+            .collect()
+    }
+
+    fn next_version(&mut self) -> String {
+        self.resource_version += 1;
+        self.resource_version.to_string()
+    }
+
+    /// Handle an API request.
+    pub fn handle(&mut self, parts: &Parts, body: &[u8]) -> Response<Body> {
+        let path = match ApiPath::parse(parts.uri.path()) {
+            Some(path) => path,
+            None => {
+                return Failure::new(
+                    StatusCode::NOT_FOUND,
+                    "NotFound",
+                    format!(
+                        "the server could not find the requested resource ({})",
+                        parts.uri
+                    ),
+                )
+                .into_response()
+            }
+        };
+
+        let query: HashMap<String, String> = parts
+            .uri
+            .query()
+            .map(|query| {
+                form_urlencoded::parse(query.as_bytes())
+                    .into_owned()
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        log::debug!("{} {:?} - {:?}", parts.method, path, query);
+
+        if parts.method == Method::GET && path.name.is_none() && is_watch(&query) {
+            return self
+                .watch(path, &query)
+                .unwrap_or_else(Failure::into_response);
+        }
+
+        let result = match (&parts.method, path.name.is_some()) {
+            (&Method::GET, true) => self
+                .get(&path)
+                .map(|value| (StatusCode::OK, value))
+                .ok_or_else(|| Failure::not_found(&path)),
+            (&Method::GET, false) => self.list_matching(&path, &query),
+            (&Method::POST, false) => self.create(&path, body, dry_run(&query)),
+            (&Method::PUT, true) => self.replace(&path, body, dry_run(&query)),
+            (&Method::PATCH, true) => {
+                let content_type = parts
+                    .headers
+                    .get(CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default();
+                self.patch(&path, content_type, body, dry_run(&query))
+            }
+            (&Method::DELETE, true) => self.delete(&path, body),
+            (&Method::DELETE, false) => self.delete_collection(&path, &query, body),
+            (method, _) => Err(Failure::new(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "MethodNotAllowed",
+                format!("{} is not supported on {}", method, parts.uri.path()),
+            )),
+        };
+
+        match result {
+            Ok((code, value)) => respond(code, &value),
+            Err(failure) => failure.into_response(),
+        }
+    }
+
+    fn list_matching(
+        &self,
+        path: &ApiPath,
+        query: &HashMap<String, String>,
+    ) -> Result<(StatusCode, Value)> {
+        let filter = Filter::from_query(query)?;
+        let items: Vec<Value> = self
+            .list(path)
+            .into_iter()
+            .filter(|item| filter.matches(item))
+            .collect();
+
+        Ok((
+            StatusCode::OK,
+            json!({
+                "kind": "List",
+                "apiVersion": "v1",
+                "metadata": {
+                    "resourceVersion": self.resource_version.to_string(),
+                },
+                "items": items,
+            }),
+        ))
+    }
+
+    fn watch(&mut self, path: ApiPath, query: &HashMap<String, String>) -> Result<Response<Body>> {
+        let filter = Filter::from_query(query)?;
+        let version = query
+            .get("resourceVersion")
+            .and_then(|version| version.parse::<u64>().ok())
+            .unwrap_or_default();
+
+        let (sender, receiver) = mpsc::unbounded();
+        let watcher = Watcher {
+            path,
+            filter,
+            sender,
+        };
+
+        if version == 0 {
+            // start with the current state
+            for (key, object) in &self.resources {
+                if watcher.matches(key, object) {
+                    watcher.send("ADDED", object);
+                }
+            }
+        } else if version < self.compacted {
+            // the changes since the requested version are no longer available
+            watcher.send(
+                "ERROR",
+                &json!({
+                    "kind": "Status",
+                    "apiVersion": "v1",
+                    "metadata": {},
+                    "status": "Failure",
+                    "message": format!("too old resource version: {} ({})", version, self.compacted),
+                    "reason": "Expired",
+                    "code": 410,
+                }),
+            );
+            // the watch ends right away, as the watcher gets dropped
+            return Ok(watch_response(receiver));
+        } else {
+            // replay what happened since the requested version
+            for event in &self.events {
+                if event.resource_version > version && watcher.matches(&event.key, &event.object) {
+                    watcher.send(event.r#type, &event.object);
+                }
+            }
+        }
+
+        self.watchers.push(watcher);
+        Ok(watch_response(receiver))
+    }
+
+    fn create(
+        &mut self,
+        path: &ApiPath,
+        body: &[u8],
+        dry_run: bool,
+    ) -> Result<(StatusCode, Value)> {
+        let mut value = parse_body(body)?;
+        let meta = metadata(&mut value);
+
+        if let (Some(namespace), Some(Value::String(requested))) =
+            (&path.namespace, meta.get("namespace"))
+        {
+            if namespace != requested {
+                return Err(Failure::bad_request(
+                    "the namespace of the provided object does not match the namespace sent on the request",
+                ));
+            }
+        }
+
+        let name = match (meta.get("name"), meta.get("generateName")) {
+            (Some(Value::String(name)), _) if !name.is_empty() => name.clone(),
+            (_, Some(Value::String(prefix))) => format!("{}{}", prefix, suffix()),
+            _ => {
+                return Err(Failure::new(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "Invalid",
+                    "name or generateName is required",
+                ))
+            }
+        };
+
+        let path = path.clone().with_name(name.clone());
+        if self.resources.contains_key(&path.key()) {
+            return Err(Failure::new(
+                StatusCode::CONFLICT,
+                "AlreadyExists",
+                format!("{} already exists", path.describe()),
+            ));
+        }
+
+        meta.insert("name".into(), name.into());
+        if let Some(namespace) = &path.namespace {
+            meta.insert("namespace".into(), namespace.clone().into());
+        }
+        meta.insert("uid".into(), uid().into());
+        meta.insert("creationTimestamp".into(), now().into());
+        meta.remove("deletionTimestamp");
+        if value.get("spec").is_some() {
+            metadata(&mut value).insert("generation".into(), 1.into());
+        }
+        // the status can only be set through the status subresource
+        if let Some(object) = value.as_object_mut() {
+            object.remove("status");
+        }
+
+        let value = self.store(&path, value, dry_run);
+        Ok((StatusCode::CREATED, value))
+    }
+
+    fn replace(
+        &mut self,
+        path: &ApiPath,
+        body: &[u8],
+        dry_run: bool,
+    ) -> Result<(StatusCode, Value)> {
+        let current = self.get(path).ok_or_else(|| Failure::not_found(path))?;
+        let value = parse_body(body)?;
+
+        if let Some(Value::String(name)) = value.pointer("/metadata/name") {
+            if Some(name) != path.name.as_ref() {
+                return Err(Failure::bad_request(
+                    "the name of the object does not match the name on the URL",
+                ));
+            }
+        }
+        check_resource_version(path, &current, &value)?;
+
+        self.update(path, current, value, dry_run)
+    }
+
+    fn patch(
+        &mut self,
+        path: &ApiPath,
+        content_type: &str,
+        body: &[u8],
+        dry_run: bool,
+    ) -> Result<(StatusCode, Value)> {
+        let patch = parse_body(body)?;
+
+        let current = match (self.get(path), content_type) {
+            (Some(current), _) => current,
+            // applying creates missing resources
+            (None, "application/apply-patch+yaml") => {
+                let mut value = patch;
+                metadata(&mut value).insert("name".into(), path.name.clone().into());
+                let status = value.as_object_mut().and_then(|o| o.remove("status"));
+                let (code, mut created) =
+                    self.create(path, value.to_string().as_bytes(), dry_run)?;
+                if let (Some(status), Some("status")) = (status, path.subresource.as_deref()) {
+                    created["status"] = status;
+                    created = self.store(path, created, dry_run);
+                }
+                return Ok((code, created));
+            }
+            (None, _) => return Err(Failure::not_found(path)),
+        };
+
+        let mut value = current.clone();
+        match content_type {
+            "application/json-patch+json" => {
+                let patch: json_patch::Patch = serde_json::from_value(patch)
+                    .map_err(|err| Failure::bad_request(err.to_string()))?;
+                json_patch::patch(&mut value, &patch).map_err(|err| {
+                    Failure::new(StatusCode::UNPROCESSABLE_ENTITY, "Invalid", err.to_string())
+                })?;
+            }
+            "application/merge-patch+json"
+            | "application/strategic-merge-patch+json"
+            | "application/apply-patch+yaml" => {
+                json_patch::merge(&mut value, &patch);
+            }
+            _ => {
+                return Err(Failure::new(
+                    StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                    "UnsupportedMediaType",
+                    format!("the content type '{}' is not supported", content_type),
+                ))
+            }
+        }
+
+        check_resource_version(path, &current, &value)?;
+
+        self.update(path, current, value, dry_run)
+    }
+
+    fn delete(&mut self, path: &ApiPath, body: &[u8]) -> Result<(StatusCode, Value)> {
+        let options = delete_options(body)?;
+
+        match self.delete_one(path, &options)? {
+            (current, false) => Ok((StatusCode::OK, current)),
+            (current, true) => Ok((
+                StatusCode::OK,
+                json!({
+                    "kind": "Status",
+                    "apiVersion": "v1",
+                    "metadata": {},
+                    "status": "Success",
+                    "details": {
+                        "name": path.name,
+                        "kind": path.plural,
+                        "uid": current.pointer("/metadata/uid"),
+                    },
+                }),
+            )),
+        }
+    }
+
+    fn delete_collection(
+        &mut self,
+        path: &ApiPath,
+        query: &HashMap<String, String>,
+        body: &[u8],
+    ) -> Result<(StatusCode, Value)> {
+        if self
+            .no_delete_collection
+            .contains(&(path.api.clone(), path.plural.clone()))
+        {
+            return Err(Failure::new(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "MethodNotAllowed",
+                format!("the server does not allow this method on {}", path.plural),
+            ));
+        }
+
+        let options = delete_options(body)?;
+        let filter = Filter::from_query(query)?;
+
+        let matching: Vec<ApiPath> = self
+            .resources
+            .iter()
+            .filter(|(key, value)| path.contains(key) && filter.matches(value))
+            .map(|(key, _)| ApiPath::from_key(key))
+            .collect();
+
+        let mut items = Vec::with_capacity(matching.len());
+        for path in matching {
+            items.push(self.delete_one(&path, &options)?.0);
+        }
+
+        if self
+            .status_delete_collection
+            .contains(&(path.api.clone(), path.plural.clone()))
+        {
+            return Ok((
+                StatusCode::OK,
+                json!({
+                    "kind": "Status",
+                    "apiVersion": "v1",
+                    "metadata": {},
+                    "status": "Success",
+                }),
+            ));
+        }
+
+        Ok((
+            StatusCode::OK,
+            json!({
+                "kind": "List",
+                "apiVersion": "v1",
+                "metadata": {
+                    "resourceVersion": self.resource_version.to_string(),
+                },
+                "items": items,
+            }),
+        ))
+    }
+
+    /// Delete a single resource, returns the resource and whether it got removed.
+    fn delete_one(&mut self, path: &ApiPath, options: &Value) -> Result<(Value, bool)> {
+        let mut current = self.get(path).ok_or_else(|| Failure::not_found(path))?;
+        let dry_run = matches!(options.get("dryRun"), Some(Value::Array(v)) if !v.is_empty());
+
+        for (field, name) in [("uid", "UID"), ("resourceVersion", "ResourceVersion")] {
+            let expected = options
+                .pointer(&format!("/preconditions/{}", field))
+                .and_then(Value::as_str);
+            let actual = current
+                .pointer(&format!("/metadata/{}", field))
+                .and_then(Value::as_str)
+                .unwrap_or_default();
+            if let Some(expected) = expected {
+                if expected != actual {
+                    return Err(Failure::conflict(
+                        path,
+                        format!(
+                            "Precondition failed: {} in precondition: {}, {} in object meta: {}",
+                            name, expected, name, actual
+                        ),
+                    ));
+                }
+            }
+        }
+
+        if has_finalizers(&current) {
+            // mark for deletion, the resource gets removed once all finalizers are gone
+            let meta = metadata(&mut current);
+            if !meta.contains_key("deletionTimestamp") {
+                meta.insert("deletionTimestamp".into(), now().into());
+                current = self.store(path, current, dry_run);
+            }
+            return Ok((current, false));
+        }
+
+        if !dry_run {
+            self.remove(path);
+        }
+
+        Ok((current, true))
+    }
+
+    /// Update an existing resource, with a new (unchecked) value.
+    fn update(
+        &mut self,
+        path: &ApiPath,
+        current: Value,
+        value: Value,
+        dry_run: bool,
+    ) -> Result<(StatusCode, Value)> {
+        let mut value = match path.subresource.as_deref() {
+            // the main resource keeps the current status
+            None => {
+                let mut value = value;
+                match current.get("status") {
+                    Some(status) => value["status"] = status.clone(),
+                    None => {
+                        value.as_object_mut().map(|o| o.remove("status"));
+                    }
+                }
+                value
+            }
+            // the status subresource only changes the status
+            Some("status") => {
+                let mut update = current.clone();
+                match value.get("status") {
+                    Some(status) => update["status"] = status.clone(),
+                    None => {
+                        update.as_object_mut().map(|o| o.remove("status"));
+                    }
+                }
+                update
+            }
+            Some(sub) => {
+                return Err(Failure::not_found(path)
+                    .with_message(format!("the subresource '{}' is not supported", sub)))
+            }
+        };
+
+        // keep the metadata managed by the server
+        let current_meta = current["metadata"].clone();
+        let meta = metadata(&mut value);
+        for field in [
+            "name",
+            "namespace",
+            "uid",
+            "creationTimestamp",
+            "deletionTimestamp",
+            "generation",
+            "resourceVersion",
+        ] {
+            match current_meta.get(field) {
+                Some(v) => meta.insert(field.into(), v.clone()),
+                None => meta.remove(field),
+            };
+        }
+
+        // no-op updates don't create a new version
+        if value == current {
+            return Ok((StatusCode::OK, current));
+        }
+
+        if value.get("spec") != current.get("spec") {
+            let generation = current_meta
+                .get("generation")
+                .and_then(Value::as_i64)
+                .unwrap_or_default();
+            metadata(&mut value).insert("generation".into(), (generation + 1).into());
+        }
+
+        let deleted = current_meta.get("deletionTimestamp").is_some() && !has_finalizers(&value);
+        let value = self.store(path, value, dry_run);
+        if deleted && !dry_run {
+            // the last finalizer is gone
+            self.remove(path);
+        }
+
+        Ok((StatusCode::OK, value))
+    }
+
+    /// Store a resource with a new resource version, unless it is a dry run.
+    fn store(&mut self, path: &ApiPath, value: Value, dry_run: bool) -> Value {
+        match dry_run {
+            true => value,
+            false => self.put(path, value),
+        }
+    }
+
+    /// Persist a resource with a new resource version.
+    fn put(&mut self, path: &ApiPath, mut value: Value) -> Value {
+        let version = self.next_version();
+        metadata(&mut value).insert("resourceVersion".into(), version.into());
+        let r#type = match self.resources.insert(path.key(), value.clone()) {
+            Some(_) => "MODIFIED",
+            None => "ADDED",
+        };
+        self.notify(r#type, path.key(), &value);
+        value
+    }
+
+    /// Remove a resource, which also creates a new resource version.
+    fn remove(&mut self, path: &ApiPath) {
+        if let Some(mut value) = self.resources.remove(&path.key()) {
+            let version = self.next_version();
+            metadata(&mut value).insert("resourceVersion".into(), version.into());
+            self.notify("DELETED", path.key(), &value);
+        }
+    }
+
+    /// Record an event, and send it to all matching watches.
+    fn notify(&mut self, r#type: &'static str, key: Key, object: &Value) {
+        // drop watches which got closed
+        self.watchers
+            .retain(|watcher| !watcher.matches(&key, object) || watcher.send(r#type, object));
+        self.events.push_back(Event {
+            resource_version: self.resource_version,
+            r#type,
+            key,
+            object: object.clone(),
+        });
+        while self.events.len() > MAX_EVENTS {
+            if let Some(event) = self.events.pop_front() {
+                self.compacted = event.resource_version;
+            }
+        }
+    }
+}
+
+/// The streaming response of a watch.
+fn watch_response(receiver: UnboundedReceiver<Bytes>) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::wrap_stream(receiver.map(Ok::<_, Infallible>)))
+        .unwrap()
+}
+
+/// Check the resource version of an update, if it carries one.
+fn check_resource_version(path: &ApiPath, current: &Value, update: &Value) -> Result<()> {
+    let expected = update.pointer("/metadata/resourceVersion");
+    match expected {
+        Some(Value::String(expected)) if !expected.is_empty() => {
+            if Some(expected.as_str())
+                != current
+                    .pointer("/metadata/resourceVersion")
+                    .and_then(Value::as_str)
+            {
+                return Err(Failure::conflict(
+                    path,
+                    "the object has been modified; please apply your changes to the latest version and try again",
+                ));
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Parse the (optional) `DeleteOptions` of a request.
+fn delete_options(body: &[u8]) -> Result<Value> {
+    match body.is_empty() {
+        true => Ok(Value::Null),
+        false => parse_body(body),
+    }
+}
+
+fn parse_body(body: &[u8]) -> Result<Value> {
+    match serde_json::from_slice(body) {
+        Ok(value @ Value::Object(_)) => Ok(value),
+        Ok(_) => Err(Failure::bad_request("the request body must be an object")),
+        Err(err) => Err(Failure::bad_request(format!(
+            "failed to parse request body: {}",
+            err
+        ))),
+    }
+}
+
+/// Get the metadata of an object, creating it if necessary.
+fn metadata(value: &mut Value) -> &mut serde_json::Map<String, Value> {
+    if !value["metadata"].is_object() {
+        value["metadata"] = json!({});
+    }
+    value["metadata"].as_object_mut().unwrap()
+}
+
+fn labels(value: &Value) -> BTreeMap<String, String> {
+    value
+        .pointer("/metadata/labels")
+        .and_then(Value::as_object)
+        .map(|labels| {
+            labels
+                .iter()
+                .filter_map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string())))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn has_finalizers(value: &Value) -> bool {
+    matches!(value.pointer("/metadata/finalizers"), Some(Value::Array(f)) if !f.is_empty())
+}
+
+/// Get the value of a field, as used by field selectors (e.g. `metadata.name`).
+fn field_value(value: &Value, field: &str) -> String {
+    let pointer = format!("/{}", field.replace('.', "/"));
+    match value.pointer(&pointer) {
+        Some(Value::String(s)) => s.clone(),
+        Some(Value::Null) | None => String::new(),
+        Some(other) => other.to_string(),
+    }
+}
+
+/// Parse a field selector into a list of `(field, equal, value)` requirements.
+fn parse_field_selector(selector: &str) -> Result<Vec<(String, bool, String)>> {
+    let mut result = Vec::new();
+
+    for term in split_unescaped(selector, ',') {
+        if term.is_empty() {
+            continue;
+        }
+        let (field, equal, value) = if let Some((field, value)) = term.split_once("!=") {
+            (field, false, value)
+        } else if let Some((field, value)) = term.split_once("==") {
+            (field, true, value)
+        } else if let Some((field, value)) = term.split_once('=') {
+            (field, true, value)
+        } else {
+            return Err(Failure::bad_request(format!(
+                "invalid field selector: '{}'",
+                term
+            )));
+        };
+        result.push((field.trim().to_string(), equal, unescape(value)));
+    }
+
+    Ok(result)
+}
+
+/// Split a string at a separator, skipping separators escaped by a backslash.
+fn split_unescaped(s: &str, separator: char) -> Vec<String> {
+    let mut result = vec![String::new()];
+    let mut escaped = false;
+    for c in s.chars() {
+        match c {
+            _ if escaped => {
+                escaped = false;
+                result.last_mut().unwrap().push(c);
+            }
+            '\\' => {
+                escaped = true;
+                result.last_mut().unwrap().push(c);
+            }
+            c if c == separator => result.push(String::new()),
+            c => result.last_mut().unwrap().push(c),
+        }
+    }
+    result
+}
+
+fn unescape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => result.extend(chars.next()),
+            c => result.push(c),
+        }
+    }
+    result
+}
+
+fn is_watch(query: &HashMap<String, String>) -> bool {
+    matches!(query.get("watch").map(String::as_str), Some("true" | "1"))
+}
+
+fn dry_run(query: &HashMap<String, String>) -> bool {
+    query.get("dryRun").map(String::as_str) == Some("All")
+}
+
+fn now() -> String {
+    Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true)
+}
+
+fn uid() -> String {
+    let mut rng = rand::thread_rng();
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        rng.gen::<u32>(),
+        rng.gen::<u16>(),
+        rng.gen::<u16>(),
+        rng.gen::<u16>(),
+        rng.gen::<u64>() & 0xffff_ffff_ffff
+    )
+}
+
+/// The random suffix for generated names.
+fn suffix() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(5)
+        .map(|c| (c as char).to_ascii_lowercase())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+
+    use super::*;
+
+    #[test]
+    fn test_parse_path() {
+        assert_eq!(
+            ApiPath::parse("/api/v1/namespaces/default/configmaps/foo"),
+            Some(ApiPath {
+                api: "api/v1".into(),
+                plural: "configmaps".into(),
+                namespace: Some("default".into()),
+                name: Some("foo".into()),
+                subresource: None,
+            })
+        );
+        assert_eq!(
+            ApiPath::parse("/apis/apps/v1/namespaces/default/deployments/foo/status"),
+            Some(ApiPath {
+                api: "apis/apps/v1".into(),
+                plural: "deployments".into(),
+                namespace: Some("default".into()),
+                name: Some("foo".into()),
+                subresource: Some("status".into()),
+            })
+        );
+        assert_eq!(
+            ApiPath::parse("/apis/apps/v1/deployments"),
+            Some(ApiPath {
+                api: "apis/apps/v1".into(),
+                plural: "deployments".into(),
+                namespace: None,
+                name: None,
+                subresource: None,
+            })
+        );
+        assert_eq!(
+            ApiPath::parse("/api/v1/namespaces/default"),
+            Some(ApiPath {
+                api: "api/v1".into(),
+                plural: "namespaces".into(),
+                namespace: None,
+                name: Some("default".into()),
+                subresource: None,
+            })
+        );
+        assert_eq!(
+            ApiPath::parse("/api/v1/namespaces/default/status"),
+            Some(ApiPath {
+                api: "api/v1".into(),
+                plural: "namespaces".into(),
+                namespace: None,
+                name: Some("default".into()),
+                subresource: Some("status".into()),
+            })
+        );
+        assert_eq!(
+            ApiPath::parse("/api/v1/namespaces/default/finalize"),
+            Some(ApiPath {
+                api: "api/v1".into(),
+                plural: "namespaces".into(),
+                namespace: None,
+                name: Some("default".into()),
+                subresource: Some("finalize".into()),
+            })
+        );
+        assert_eq!(ApiPath::parse("/version"), None);
+    }
+
+    #[test]
+    fn test_events_capped() {
+        let mut store = Store::default();
+        let path = ApiPath::parse("/api/v1/namespaces/default/configmaps/foo").unwrap();
+
+        for _ in 0..MAX_EVENTS + 10 {
+            store.insert(&path, json!({"metadata": {"name": "foo"}}));
+        }
+
+        assert_eq!(store.events.len(), MAX_EVENTS);
+        assert_eq!(store.compacted, 10);
+        assert_eq!(store.events.front().unwrap().resource_version, 11);
+    }
+
+    #[test]
+    fn test_field_selector() {
+        assert_eq!(
+            parse_field_selector(r"metadata.name=foo\,bar,status.phase!=Running").unwrap(),
+            vec![
+                ("metadata.name".to_string(), true, "foo,bar".to_string()),
+                ("status.phase".to_string(), false, "Running".to_string()),
+            ]
+        );
+        assert!(parse_field_selector("metadata.name").is_err());
+    }
+}
@@ -10,14 +10,19 @@
  *
  * SPDX-License-Identifier: EPL-2.0
  */
+use crate::{selectors, utils::UseOrCreate};
 use k8s_openapi::{
-    api::core::v1::{ConfigMap, Secret},
+    api::{
+        apps::v1::{DaemonSet, Deployment, StatefulSet},
+        core::v1::{ConfigMap, PodTemplateSpec, Secret},
+    },
     ByteString,
 };
 use sha1::{Digest, Sha1};
 use std::{
     collections::BTreeMap,
     fmt::{Display, Formatter},
+    str::FromStr,
 };
 
 /// Tracking content changes of configurations.
@@ -28,22 +33,173 @@ use std::{
 /// result a changed hash, and thus a change in the PodSpec, resulting in a redeployment.
 pub struct ConfigTracker {
     sha: Sha1,
+    encoding: Encoding,
+    empty: bool,
+    name: Option<TrackerName>,
+}
+
+/// The encoding a [`ConfigTracker`] uses for feeding data into the hash.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// The original encoding, which concatenates the tracked data.
+    ///
+    /// This encoding is ambiguous: it ignores the keys of maps, and some of the data fields of
+    /// ConfigMaps and Secrets. It is still the default, as switching the encoding changes the
+    /// hashes, and thus results in a redeployment.
+    #[default]
+    Legacy,
+    /// A versioned encoding, which length-prefixes all data, and covers keys as well as
+    /// all data fields.
+    V2,
 }
 
 pub trait Trackable {
     fn track_with(&self, tracker: &mut ConfigTracker);
 }
 
+impl Default for ConfigTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl ConfigTracker {
+    /// Create a new tracker, using the default (legacy) encoding.
     pub fn new() -> Self {
-        ConfigTracker { sha: Sha1::new() }
+        Self::with_encoding(Default::default())
+    }
+
+    /// Create a new tracker, using the legacy encoding.
+    pub fn legacy() -> Self {
+        Self::with_encoding(Encoding::Legacy)
+    }
+
+    /// Create a new tracker, using the [`Encoding::V2`] encoding.
+    ///
+    /// Switching existing trackers to this encoding changes their hashes once.
+    pub fn v2() -> Self {
+        Self::with_encoding(Encoding::V2)
+    }
+
+    pub fn with_encoding(encoding: Encoding) -> Self {
+        ConfigTracker {
+            sha: Sha1::new(),
+            encoding,
+            empty: true,
+            name: None,
+        }
+    }
+
+    /// Create a new named tracker, using the default encoding.
+    ///
+    /// Fails if the name can't be used as part of the annotation, see [`TrackerName`].
+    pub fn named<S>(name: S) -> Result<Self, InvalidTrackerName>
+    where
+        S: Into<String>,
+    {
+        Ok(Self::new().with_name(TrackerName::new(name)?))
+    }
+
+    /// Set the name of the tracker.
+    pub fn with_name(mut self, name: TrackerName) -> Self {
+        self.name = Some(name);
+        self
+    }
+
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    pub fn name(&self) -> Option<&TrackerName> {
+        self.name.as_ref()
+    }
+
+    /// Check if nothing has been tracked so far.
+    pub fn is_empty(&self) -> bool {
+        self.empty
     }
 
     pub fn track<D>(&mut self, data: D)
     where
         D: AsRef<[u8]>,
     {
-        self.sha.update(data.as_ref());
+        let data = data.as_ref();
+        match self.encoding {
+            Encoding::Legacy => {
+                self.empty = false;
+                self.sha.update(data);
+            }
+            Encoding::V2 => {
+                if self.empty {
+                    // identify the encoding, so that hashes never match those of other encodings
+                    self.empty = false;
+                    self.sha.update(b"v2\0");
+                }
+                self.sha.update((data.len() as u64).to_be_bytes());
+                self.sha.update(data);
+            }
+        }
+    }
+
+    /// Track a key/value pair, e.g. of a map.
+    ///
+    /// The legacy encoding only tracks the value.
+    pub fn track_entry<K, V>(&mut self, key: K, value: V)
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+    {
+        if self.encoding != Encoding::Legacy {
+            self.track(key);
+        }
+        self.track(value);
+    }
+
+    /// Track a map of key/value pairs.
+    pub fn track_map<K, V, I>(&mut self, entries: I)
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+        I: ExactSizeIterator<Item = (K, V)>,
+    {
+        if self.encoding != Encoding::Legacy {
+            self.track((entries.len() as u64).to_be_bytes());
+        }
+        for (k, v) in entries {
+            self.track_entry(k, v);
+        }
+    }
+
+    /// Track a sequence of values.
+    fn track_values<V, I>(&mut self, values: I)
+    where
+        V: AsRef<[u8]>,
+        I: ExactSizeIterator<Item = V>,
+    {
+        if self.encoding != Encoding::Legacy {
+            self.track((values.len() as u64).to_be_bytes());
+        }
+        for v in values {
+            self.track(v);
+        }
+    }
+
+    /// Track an optional map, as a named section.
+    ///
+    /// A missing map is treated like an empty map. The legacy encoding ignores the name.
+    fn track_section<K, V, I>(&mut self, name: &str, entries: Option<I>)
+    where
+        K: AsRef<[u8]>,
+        V: AsRef<[u8]>,
+        I: ExactSizeIterator<Item = (K, V)>,
+    {
+        if self.encoding != Encoding::Legacy {
+            self.track(name);
+        }
+        match entries {
+            Some(entries) => self.track_map(entries),
+            None => self.track_map(std::iter::empty::<(K, V)>()),
+        }
     }
 
     pub fn current_hash(&self) -> String {
@@ -54,6 +210,14 @@ impl ConfigTracker {
     pub fn freeze(self) -> TrackerState {
         TrackerState(self.current_hash())
     }
+
+    /// Freeze the current tracker state, returning `None` if nothing was tracked.
+    pub fn freeze_non_empty(self) -> Option<TrackerState> {
+        match self.is_empty() {
+            true => None,
+            false => Some(self.freeze()),
+        }
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq)]
@@ -83,38 +247,231 @@ impl Trackable for TrackerState {
     }
 }
 
-impl<K> Trackable for BTreeMap<K, String> {
+/// Tracks keys and values, the legacy encoding only tracks the values.
+impl<K> Trackable for BTreeMap<K, String>
+where
+    K: AsRef<str>,
+{
     fn track_with(&self, tracker: &mut ConfigTracker) {
-        for (_, v) in self.iter() {
-            tracker.track(v.as_bytes());
+        match tracker.encoding() {
+            Encoding::Legacy => tracker.track_values(self.values()),
+            _ => tracker.track_map(self.iter().map(|(k, v)| (k.as_ref(), v))),
         }
     }
 }
 
-impl<K> Trackable for BTreeMap<K, ByteString> {
+/// Tracks keys and values, the legacy encoding only tracks the values.
+impl<K> Trackable for BTreeMap<K, ByteString>
+where
+    K: AsRef<str>,
+{
     fn track_with(&self, tracker: &mut ConfigTracker) {
-        for (_, v) in self.iter() {
-            tracker.track(v.0.as_slice());
+        match tracker.encoding() {
+            Encoding::Legacy => tracker.track_values(self.values().map(|v| &v.0)),
+            _ => tracker.track_map(self.iter().map(|(k, v)| (k.as_ref(), &v.0))),
         }
     }
 }
 
 impl Trackable for Secret {
     fn track_with(&self, tracker: &mut ConfigTracker) {
-        if let Some(data) = &self.data {
-            data.track_with(tracker);
+        match tracker.encoding() {
+            Encoding::Legacy => {
+                if let Some(data) = &self.data {
+                    data.track_with(tracker);
+                }
+            }
+            Encoding::V2 => {
+                tracker.track_section(
+                    "data",
+                    self.data
+                        .as_ref()
+                        .map(|data| data.iter().map(|(k, v)| (k, &v.0))),
+                );
+                tracker.track_section("stringData", self.string_data.as_ref().map(|d| d.iter()));
+            }
         }
     }
 }
 
 impl Trackable for ConfigMap {
     fn track_with(&self, tracker: &mut ConfigTracker) {
-        if let Some(data) = &self.data {
-            data.track_with(tracker);
+        match tracker.encoding() {
+            Encoding::Legacy => {
+                if let Some(data) = &self.data {
+                    data.track_with(tracker);
+                }
+            }
+            Encoding::V2 => {
+                tracker.track_section("data", self.data.as_ref().map(|d| d.iter()));
+                tracker.track_section(
+                    "binaryData",
+                    self.binary_data
+                        .as_ref()
+                        .map(|data| data.iter().map(|(k, v)| (k, &v.0))),
+                );
+            }
+        }
+    }
+}
+
+/// The annotation carrying the tracker state on a pod template.
+pub const CONFIG_HASH_ANNOTATION: &str = "operator-framework/config-hash";
+
+/// The name of a tracker, which can be used as part of the annotation key.
+///
+/// The annotation key of a named tracker is `operator-framework/config-hash-<name>`, which must be
+/// a valid annotation key. So the name must consist of alphanumeric characters, `-`, `_` or `.`,
+/// must end with an alphanumeric character, and must not be longer than 51 characters.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TrackerName(String);
+
+impl TrackerName {
+    pub fn new<S>(name: S) -> Result<Self, InvalidTrackerName>
+    where
+        S: Into<String>,
+    {
+        let name = name.into();
+        if name.is_empty() {
+            return Err(InvalidTrackerName {
+                name,
+                reason: "name must not be empty".into(),
+            });
+        }
+
+        match selectors::validate_key(&annotation(&name)) {
+            Ok(()) => Ok(Self(name)),
+            Err(selectors::Error::InvalidKey { reason, .. }) => {
+                Err(InvalidTrackerName { name, reason })
+            }
+            Err(err) => Err(InvalidTrackerName {
+                name,
+                reason: err.to_string(),
+            }),
         }
     }
+
+    /// The annotation key carrying the state of a tracker with this name.
+    pub fn annotation(&self) -> String {
+        annotation(&self.0)
+    }
 }
 
+fn annotation(name: &str) -> String {
+    format!("{}-{}", CONFIG_HASH_ANNOTATION, name)
+}
+
+impl AsRef<str> for TrackerName {
+    fn as_ref(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for TrackerName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl FromStr for TrackerName {
+    type Err = InvalidTrackerName;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::new(s)
+    }
+}
+
+/// A tracker name, which can't be used as part of an annotation key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct InvalidTrackerName {
+    pub name: String,
+    pub reason: String,
+}
+
+impl Display for InvalidTrackerName {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid tracker name '{}': {}", self.name, self.reason)
+    }
+}
+
+impl std::error::Error for InvalidTrackerName {}
+
+/// Apply the state of config trackers to a pod template, as annotations.
+///
+/// Changing the annotation of a pod template results in a rollout of the workload. Multiple
+/// trackers, e.g. one per config source, can be applied by giving them a name.
+pub trait ApplyTrackerState {
+    /// Set or remove an annotation on the pod template.
+    fn apply_template_annotation(&mut self, key: String, value: Option<String>);
+
+    /// Set the state of the default tracker, or remove it when `None`.
+    fn apply_tracker_state(&mut self, state: Option<TrackerState>) {
+        self.apply_template_annotation(
+            CONFIG_HASH_ANNOTATION.to_string(),
+            state.map(|state| state.0),
+        );
+    }
+
+    /// Set the state of a named tracker, or remove it when `None`.
+    fn apply_named_tracker_state(&mut self, name: &TrackerName, state: Option<TrackerState>) {
+        self.apply_template_annotation(name.annotation(), state.map(|state| state.0));
+    }
+
+    /// Apply the state of a tracker, removing it if nothing was tracked.
+    ///
+    /// Named trackers use their own annotation, see [`ConfigTracker::named`].
+    fn apply_tracker(&mut self, tracker: ConfigTracker) {
+        match tracker.name.clone() {
+            Some(name) => self.apply_named_tracker_state(&name, tracker.freeze_non_empty()),
+            None => self.apply_tracker_state(tracker.freeze_non_empty()),
+        }
+    }
+}
+
+impl ApplyTrackerState for PodTemplateSpec {
+    fn apply_template_annotation(&mut self, key: String, value: Option<String>) {
+        match value {
+            Some(value) => self.metadata.use_or_create(|metadata| {
+                metadata.annotations.use_or_create(|annotations| {
+                    annotations.insert(key, value);
+                })
+            }),
+            None => {
+                if let Some(annotations) = self
+                    .metadata
+                    .as_mut()
+                    .and_then(|metadata| metadata.annotations.as_mut())
+                {
+                    annotations.remove(&key);
+                }
+            }
+        }
+    }
+}
+
+macro_rules! apply_tracker_state {
+    ($n:ty) => {
+        impl ApplyTrackerState for $n {
+            fn apply_template_annotation(&mut self, key: String, value: Option<String>) {
+                match value {
+                    Some(value) => self.spec.use_or_create(|spec| {
+                        spec.template.apply_template_annotation(key, Some(value))
+                    }),
+                    None => {
+                        if let Some(spec) = &mut self.spec {
+                            spec.template.apply_template_annotation(key, None);
+                        }
+                    }
+                }
+            }
+        }
+    };
+}
+
+apply_tracker_state!(Deployment);
+apply_tracker_state!(StatefulSet);
+apply_tracker_state!(DaemonSet);
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -127,4 +484,189 @@ mod test {
             tracker.current_hash()
         );
     }
+
+    fn hash<T: Trackable>(mut tracker: ConfigTracker, value: &T) -> String {
+        value.track_with(&mut tracker);
+        tracker.current_hash()
+    }
+
+    fn hash_map(mut tracker: ConfigTracker, map: &BTreeMap<String, String>) -> String {
+        tracker.track_map(map.iter());
+        tracker.current_hash()
+    }
+
+    fn map(entries: &[(&str, &str)]) -> BTreeMap<String, String> {
+        entries
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_keys() {
+        let a = map(&[("foo", "1"), ("bar", "2")]);
+        let b = map(&[("foo", "2"), ("bar", "1")]);
+        let c = map(&[("baz", "1"), ("bar", "2")]);
+
+        assert_ne!(
+            hash_map(ConfigTracker::v2(), &a),
+            hash_map(ConfigTracker::v2(), &b)
+        );
+        assert_ne!(
+            hash_map(ConfigTracker::v2(), &a),
+            hash_map(ConfigTracker::v2(), &c)
+        );
+
+        // the legacy encoding only tracks the values
+        assert_eq!(
+            hash_map(ConfigTracker::legacy(), &a),
+            hash_map(ConfigTracker::legacy(), &c)
+        );
+
+        // tracking the map itself tracks the keys as well
+        assert_ne!(hash(ConfigTracker::v2(), &a), hash(ConfigTracker::v2(), &c));
+        assert_eq!(
+            hash(ConfigTracker::v2(), &a),
+            hash_map(ConfigTracker::v2(), &a)
+        );
+        assert_eq!(
+            hash(ConfigTracker::legacy(), &a),
+            hash(ConfigTracker::legacy(), &c)
+        );
+    }
+
+    #[test]
+    fn test_ambiguous() {
+        let a = map(&[("bar", "ab"), ("foo", "c")]);
+        let b = map(&[("bar", "a"), ("foo", "bc")]);
+
+        assert_ne!(hash(ConfigTracker::v2(), &a), hash(ConfigTracker::v2(), &b));
+        assert_eq!(
+            hash(ConfigTracker::legacy(), &a),
+            hash(ConfigTracker::legacy(), &b)
+        );
+    }
+
+    #[test]
+    fn test_default_encoding() {
+        assert_eq!(ConfigTracker::new().encoding(), Encoding::Legacy);
+        assert_eq!(ConfigTracker::default().encoding(), Encoding::Legacy);
+    }
+
+    #[test]
+    fn test_legacy() {
+        let mut tracker = ConfigTracker::legacy();
+        map(&[("foo", "bar")]).track_with(&mut tracker);
+
+        let mut sha = Sha1::new();
+        sha.update(b"bar");
+
+        assert_eq!(format!("{:x}", sha.finalize()), tracker.current_hash());
+    }
+
+    #[test]
+    fn test_configmap() {
+        let data = ConfigMap {
+            data: Some(map(&[("foo", "bar")])),
+            ..Default::default()
+        };
+        let binary_data = ConfigMap {
+            binary_data: Some([("foo".to_string(), ByteString("bar".into()))].into()),
+            ..Default::default()
+        };
+
+        assert_ne!(
+            hash(ConfigTracker::v2(), &data),
+            hash(ConfigTracker::v2(), &binary_data)
+        );
+        assert_ne!(
+            hash(ConfigTracker::v2(), &binary_data),
+            hash(ConfigTracker::v2(), &ConfigMap::default())
+        );
+        assert_eq!(
+            hash(ConfigTracker::legacy(), &binary_data),
+            hash(ConfigTracker::legacy(), &ConfigMap::default())
+        );
+    }
+
+    #[test]
+    fn test_secret() {
+        let string_data = Secret {
+            string_data: Some(map(&[("foo", "bar")])),
+            ..Default::default()
+        };
+
+        assert_ne!(
+            hash(ConfigTracker::v2(), &string_data),
+            hash(ConfigTracker::v2(), &Secret::default())
+        );
+    }
+
+    #[test]
+    fn test_apply_tracker() {
+        let mut deployment = Deployment::default();
+
+        let mut tracker = ConfigTracker::new();
+        tracker.track("foo");
+        let hash = tracker.current_hash();
+        deployment.apply_tracker(tracker);
+
+        let mut tracker = ConfigTracker::named("secrets").unwrap();
+        tracker.track("bar");
+        let hash_secrets = tracker.current_hash();
+        deployment.apply_tracker(tracker);
+
+        let annotations = |deployment: &Deployment| {
+            deployment
+                .spec
+                .as_ref()
+                .and_then(|spec| spec.template.metadata.as_ref())
+                .and_then(|metadata| metadata.annotations.clone())
+                .unwrap_or_default()
+        };
+
+        assert_eq!(
+            annotations(&deployment),
+            [
+                ("operator-framework/config-hash".to_string(), hash),
+                (
+                    "operator-framework/config-hash-secrets".to_string(),
+                    hash_secrets.clone()
+                ),
+            ]
+            .into()
+        );
+
+        deployment.apply_tracker(ConfigTracker::new());
+
+        assert_eq!(
+            annotations(&deployment),
+            [(
+                "operator-framework/config-hash-secrets".to_string(),
+                hash_secrets
+            )]
+            .into()
+        );
+    }
+
+    #[test]
+    fn test_tracker_name() {
+        assert!(TrackerName::new("secrets").is_ok());
+        assert!(TrackerName::new("config.v1_a-b").is_ok());
+        assert!(TrackerName::new("a".repeat(51)).is_ok());
+
+        assert!(TrackerName::new("").is_err());
+        assert!(TrackerName::new("a".repeat(52)).is_err());
+        assert!(TrackerName::new("foo/bar").is_err());
+        assert!(TrackerName::new("foo bar").is_err());
+        assert!(TrackerName::new("foo-").is_err());
+        assert!(ConfigTracker::named("foo:bar").is_err());
+    }
+
+    #[test]
+    fn test_remove_from_empty() {
+        let mut deployment = Deployment::default();
+        deployment.apply_tracker(ConfigTracker::new());
+        assert_eq!(deployment, Deployment::default());
+    }
 }